@@ -0,0 +1,8 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=linker.ld");
+    // OpenSBI and RustSBI load the kernel 2 MiB into DRAM.
+    if std::env::var_os("CARGO_FEATURE_SBI").is_some() {
+        println!("cargo:rustc-link-arg=--defsym=KERNEL_BASE=0x80200000");
+    }
+}
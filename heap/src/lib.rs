@@ -0,0 +1,317 @@
+//! The kernel's free-list allocator, kept free of anything kernel-specific
+//! so it builds and is tested on the host.
+
+#![cfg_attr(not(test), no_std)]
+
+use core::ptr;
+
+/// Every payload is aligned to at least this.
+pub const ALIGNMENT: usize = 16;
+
+// Every block, free or allocated, starts with this header. Its size is a
+// multiple of ALIGNMENT so the payload right after it stays 16-byte aligned.
+// `next` is only meaningful while the block sits in the free list.
+#[repr(C, align(16))]
+struct Block {
+    size: usize, // including the header
+    next: *mut Block,
+}
+
+pub const HEADER_SIZE: usize = core::mem::size_of::<Block>();
+pub const MIN_BLOCK_SIZE: usize = HEADER_SIZE + ALIGNMENT;
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// First-fit free-list allocator.
+///
+/// Free blocks are kept sorted by address, so a freed block is merged with
+/// its neighbours as soon as they are adjacent in memory.
+pub struct Heap {
+    free_list: *mut Block,
+    free_bytes: usize,
+    total_bytes: usize,
+}
+
+impl Heap {
+    pub const fn empty() -> Self {
+        Self {
+            free_list: ptr::null_mut(),
+            free_bytes: 0,
+            total_bytes: 0,
+        }
+    }
+
+    /// Hand the memory range `[start, start + size)` over to the heap.
+    ///
+    /// # Safety
+    /// The range must be valid, unused memory that outlives the heap.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned_start = align_up(start, ALIGNMENT);
+        let end = (start + size) & !(ALIGNMENT - 1);
+        if end <= aligned_start || end - aligned_start < MIN_BLOCK_SIZE {
+            return;
+        }
+        let block = aligned_start as *mut Block;
+        unsafe {
+            (*block).size = end - aligned_start;
+            self.total_bytes += (*block).size;
+            self.free_bytes += (*block).size;
+            self.insert_free(block);
+        }
+    }
+
+    /// # Safety
+    /// The returned pointer must only be released through `deallocate` on
+    /// the same heap.
+    pub unsafe fn allocate(&mut self, nbytes: usize) -> Option<*mut u8> {
+        unsafe { self.allocate_aligned(nbytes, ALIGNMENT) }
+    }
+
+    /// Allocate `nbytes` whose address is a multiple of `align`, which must
+    /// be a power of two.
+    ///
+    /// # Safety
+    /// Same as `allocate`.
+    pub unsafe fn allocate_aligned(&mut self, nbytes: usize, align: usize) -> Option<*mut u8> {
+        let align = align.max(ALIGNMENT);
+        let needed = align_up(nbytes.max(8), ALIGNMENT) + HEADER_SIZE;
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut cur = self.free_list;
+        unsafe {
+            while !cur.is_null() {
+                let start = cur as usize;
+                let end = start + (*cur).size;
+                let mut payload = align_up(start + HEADER_SIZE, align);
+                // Padding in front of the block must be able to stay free on
+                // its own, otherwise those bytes would be lost.
+                if payload - HEADER_SIZE != start && payload - HEADER_SIZE - start < MIN_BLOCK_SIZE
+                {
+                    payload = align_up(start + HEADER_SIZE + MIN_BLOCK_SIZE, align);
+                }
+                let block_start = payload - HEADER_SIZE;
+                if block_start + needed <= end {
+                    let mut next = (*cur).next;
+                    let mut size = end - block_start;
+                    if size - needed >= MIN_BLOCK_SIZE {
+                        // Split: the tail stays in the free list.
+                        let rest = (block_start + needed) as *mut Block;
+                        (*rest).size = size - needed;
+                        (*rest).next = next;
+                        next = rest;
+                        size = needed;
+                    }
+                    if block_start != start {
+                        // Keep the front padding as a free block of its own.
+                        (*cur).size = block_start - start;
+                        (*cur).next = next;
+                    } else if prev.is_null() {
+                        self.free_list = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+                    let block = block_start as *mut Block;
+                    (*block).size = size;
+                    (*block).next = ptr::null_mut();
+                    self.free_bytes -= size;
+                    return Some(payload as *mut u8);
+                }
+                prev = cur;
+                cur = (*cur).next;
+            }
+        }
+        None
+    }
+
+    /// # Safety
+    /// `ptr` must come from `allocate` on this heap and not be freed twice.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            let block = ptr.sub(HEADER_SIZE) as *mut Block;
+            self.free_bytes += (*block).size;
+            self.insert_free(block);
+        }
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    // Insert `block` into the address-ordered free list and coalesce it with
+    // the previous and next free blocks when they touch.
+    unsafe fn insert_free(&mut self, block: *mut Block) {
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut cur = self.free_list;
+        unsafe {
+            while !cur.is_null() && (cur as usize) < (block as usize) {
+                prev = cur;
+                cur = (*cur).next;
+            }
+            (*block).next = cur;
+            if !cur.is_null() && block as usize + (*block).size == cur as usize {
+                (*block).size += (*cur).size;
+                (*block).next = (*cur).next;
+            }
+            if prev.is_null() {
+                self.free_list = block;
+            } else if prev as usize + (*prev).size == block as usize {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION_SIZE: usize = 64 * 1024;
+
+    // Small xorshift generator, so every run sees the same pattern.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    struct Live {
+        ptr: usize,
+        size: usize,
+        fill: u8,
+    }
+
+    // A heap over a host buffer, 16-byte aligned like the kernel's pages.
+    fn heap_with_region(buffer: &mut [u128]) -> Heap {
+        let mut heap = Heap::empty();
+        unsafe { heap.add_region(buffer.as_mut_ptr() as usize, REGION_SIZE) };
+        heap
+    }
+
+    fn free_regions(heap: &Heap) -> Vec<(usize, usize)> {
+        let mut regions = Vec::new();
+        let mut cur = heap.free_list;
+        while !cur.is_null() {
+            unsafe {
+                regions.push((cur as usize, (*cur).size));
+                cur = (*cur).next;
+            }
+        }
+        regions
+    }
+
+    fn check_free(heap: &mut Heap, live: Live) {
+        let bytes = unsafe { core::slice::from_raw_parts(live.ptr as *const u8, live.size) };
+        assert!(
+            bytes.iter().all(|&b| b == live.fill),
+            "block at {:#x} was overwritten",
+            live.ptr
+        );
+        unsafe { heap.deallocate(live.ptr as *mut u8) };
+    }
+
+    fn run_random(seed: u64, ops: usize) {
+        let mut buffer = vec![0u128; REGION_SIZE / 16];
+        let mut heap = heap_with_region(&mut buffer);
+        let total = heap.total_bytes();
+        let mut rng = Rng(seed);
+        let mut live: Vec<Live> = Vec::new();
+        for op in 0..ops {
+            if live.is_empty() || rng.below(3) != 0 {
+                let size = 1 + rng.below(512);
+                let align = ALIGNMENT << rng.below(5);
+                let ptr = match unsafe { heap.allocate_aligned(size, align) } {
+                    Some(ptr) => ptr as usize,
+                    None => continue,
+                };
+                assert_eq!(ptr % ALIGNMENT, 0, "seed {seed} op {op}: misaligned");
+                assert_eq!(ptr % align, 0, "seed {seed} op {op}: not {align}-aligned");
+                for other in &live {
+                    assert!(
+                        ptr + size <= other.ptr || other.ptr + other.size <= ptr,
+                        "seed {seed} op {op}: {ptr:#x}+{size} overlaps {:#x}+{}",
+                        other.ptr,
+                        other.size
+                    );
+                }
+                let fill = rng.next() as u8;
+                unsafe { core::ptr::write_bytes(ptr as *mut u8, fill, size) };
+                live.push(Live { ptr, size, fill });
+            } else {
+                let victim = live.swap_remove(rng.below(live.len()));
+                check_free(&mut heap, victim);
+            }
+            assert!(heap.free_bytes() <= total);
+        }
+        while let Some(victim) = live.pop() {
+            check_free(&mut heap, victim);
+        }
+        assert_eq!(heap.free_bytes(), total);
+        assert_eq!(
+            free_regions(&heap),
+            vec![(buffer.as_ptr() as usize, total)],
+            "seed {seed}: heap did not coalesce back into one region"
+        );
+    }
+
+    #[test]
+    fn random_alloc_free() {
+        for seed in [1, 0x2545_f491_4f6c_dd1d, 0xdead_beef, 42, 7777] {
+            run_random(seed, 5000);
+        }
+    }
+
+    #[test]
+    fn freed_neighbours_merge() {
+        let mut buffer = vec![0u128; REGION_SIZE / 16];
+        let mut heap = heap_with_region(&mut buffer);
+        let (a, b, c) = unsafe {
+            (
+                heap.allocate(100).unwrap(),
+                heap.allocate(100).unwrap(),
+                heap.allocate(100).unwrap(),
+            )
+        };
+        unsafe {
+            heap.deallocate(a);
+            heap.deallocate(c);
+            heap.deallocate(b);
+        }
+        assert_eq!(free_regions(&heap).len(), 1);
+        // The whole region is one block again.
+        let all = unsafe { heap.allocate(heap.total_bytes() - HEADER_SIZE) };
+        assert!(all.is_some());
+    }
+
+    #[test]
+    fn exhausted_heap_fails() {
+        let mut buffer = vec![0u128; REGION_SIZE / 16];
+        let mut heap = heap_with_region(&mut buffer);
+        assert!(unsafe { heap.allocate(REGION_SIZE) }.is_none());
+        let mut count = 0;
+        while unsafe { heap.allocate(1000) }.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, REGION_SIZE / (1008 + HEADER_SIZE));
+        assert!(heap.free_bytes() < 1008 + HEADER_SIZE);
+    }
+}
@@ -30,6 +30,34 @@ macro_rules! define_csr {
             }
         }
     };
+    // For CSRs the assembler only knows by name with the extension enabled.
+    ($csr_name:ident, $csr_number:literal) => {
+        paste! {
+            #[inline(always)]
+            pub fn [<read_ $csr_name>]() -> u64 {
+                let value: u64;
+                unsafe {
+                    asm!(
+                        concat!("csrr {0}, ", stringify!($csr_number)),
+                        out(reg) value,
+                        options(nomem, nostack, preserves_flags)
+                    );
+                }
+                value
+            }
+
+            #[inline(always)]
+            pub fn [<write_ $csr_name>](value: u64) {
+                unsafe {
+                    asm!(
+                        concat!("csrw ", stringify!($csr_number), ", {0}"),
+                        in(reg) value,
+                        options(nomem, nostack, preserves_flags)
+                    );
+                }
+            }
+        }
+    };
 }
 
 define_csr!(mhartid);
@@ -43,6 +71,8 @@ define_csr!(mepc);
 define_csr!(mcause);
 define_csr!(mtval);
 define_csr!(mip);
+define_csr!(mcounteren);
+define_csr!(menvcfg, 0x30a);
 
 define_csr!(sstatus);
 define_csr!(sie);
@@ -52,6 +82,9 @@ define_csr!(sepc);
 define_csr!(scause);
 define_csr!(stval);
 define_csr!(sip);
+define_csr!(satp);
+define_csr!(stimecmp, 0x14d);
+define_csr!(time);
 
 define_csr!(pmpaddr0);
 define_csr!(pmpcfg0);
@@ -69,11 +102,16 @@ pub const MIP_MSIP: u8 = 3;
 pub const MIP_MTIP: u8 = 7;
 pub const MIP_MEIP: u8 = 11;
 
+pub const MCOUNTEREN_TM: u8 = 1;
+pub const MENVCFG_STCE: u8 = 63;
+
 pub const SSTATUS_SIE: u8 = 1;
 pub const SSTATUS_SPIE: u8 = 5;
 pub const SSTATUS_SPP: u8 = 8;
 pub const SSTATUS_SPP_MASK: u64 = 0b1 << SSTATUS_SPP;
 
+pub const SSTATUS_SUM: u8 = 18;
+
 pub const SIE_SSIE: u8 = 1;
 pub const SIE_STIE: u8 = 5;
 pub const SIE_SEIE: u8 = 9;
@@ -82,6 +120,10 @@ pub const SIP_SSIP: u8 = 1;
 pub const SIP_STIP: u8 = 5;
 pub const SIP_SEIP: u8 = 9;
 
+pub const SATP_MODE_SV39: u64 = 8 << 60;
+pub const SATP_ASID: u8 = 44;
+pub const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
 pub fn mstatus_set_pp(pp: PrivilegeMode) {
     write_mstatus((read_mstatus() & !MSTATUS_MPP_MASK) | (pp.code() << MSTATUS_MPP))
 }
@@ -89,3 +131,23 @@ pub fn mstatus_set_pp(pp: PrivilegeMode) {
 pub fn sstatus_set_pp(pp: PrivilegeMode) {
     write_sstatus((read_sstatus() & !SSTATUS_SPP_MASK) | (pp.code() << SSTATUS_SPP))
 }
+
+pub fn make_satp_sv39(root_addr: usize, asid: u64) -> u64 {
+    SATP_MODE_SV39 | (asid << SATP_ASID) | ((root_addr as u64 >> 12) & SATP_PPN_MASK)
+}
+
+/// Flush every TLB entry on this hart.
+#[inline(always)]
+pub fn sfence_vma_all() {
+    unsafe {
+        asm!("sfence.vma zero, zero", options(nostack, preserves_flags));
+    }
+}
+
+/// Flush the TLB entries translating `vaddr`, for every address space.
+#[inline(always)]
+pub fn sfence_vma(vaddr: usize) {
+    unsafe {
+        asm!("sfence.vma {0}, zero", in(reg) vaddr, options(nostack, preserves_flags));
+    }
+}
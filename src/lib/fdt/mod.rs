@@ -0,0 +1,146 @@
+//! Just enough of a flattened device tree reader to pick single properties
+//! out of the blob the firmware or QEMU hands over in a1.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Header fields, as byte offsets.
+const HEADER_MAGIC: usize = 0;
+const HEADER_TOTALSIZE: usize = 4;
+const HEADER_OFF_DT_STRUCT: usize = 8;
+const HEADER_OFF_DT_STRINGS: usize = 12;
+
+pub struct Fdt {
+    base: usize,
+    size: usize,
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+impl Fdt {
+    /// # Safety
+    ///
+    /// `base` must be null or point to readable memory that, if it starts
+    /// with the device tree magic, holds a whole device tree blob.
+    pub unsafe fn from_addr(base: usize) -> Option<Self> {
+        if base == 0 || !base.is_multiple_of(4) {
+            return None;
+        }
+        let fdt = Self { base, size: 8 };
+        if fdt.read_u32(HEADER_MAGIC)? != FDT_MAGIC {
+            return None;
+        }
+        let size = fdt.read_u32(HEADER_TOTALSIZE)? as usize;
+        Some(Self { base, size })
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        if offset + 4 > self.size {
+            return None;
+        }
+        let word = unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) };
+        Some(u32::from_be(word))
+    }
+
+    fn read_str(&self, offset: usize) -> Option<&str> {
+        let start = (self.base + offset) as *const u8;
+        let len = (offset..self.size).position(|i| unsafe { *start.add(i - offset) } == 0)?;
+        let bytes = unsafe { core::slice::from_raw_parts(start, len) };
+        core::str::from_utf8(bytes).ok()
+    }
+
+    /// Raw value of property `name` of the first node under `/parent` that
+    /// has it, `parent` itself included.
+    pub fn find(&self, parent: &str, name: &str) -> Option<&[u8]> {
+        let strings = self.read_u32(HEADER_OFF_DT_STRINGS)? as usize;
+        let mut offset = self.read_u32(HEADER_OFF_DT_STRUCT)? as usize;
+        let mut depth = 0;
+        // Depth of `parent` while inside it.
+        let mut inside: Option<usize> = None;
+        loop {
+            let token = self.read_u32(offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node = self.read_str(offset)?;
+                    offset = align4(offset + node.len() + 1);
+                    depth += 1;
+                    // Unit addresses do not matter, `cpus` matches `cpus@0`.
+                    let node = node.split('@').next().unwrap_or(node);
+                    if inside.is_none() && depth == 2 && node == parent {
+                        inside = Some(depth);
+                    }
+                }
+                FDT_END_NODE => {
+                    if inside == Some(depth) {
+                        inside = None;
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = self.read_u32(offset)? as usize;
+                    let name_offset = self.read_u32(offset + 4)? as usize;
+                    let value = offset + 8;
+                    offset = align4(value + len);
+                    if value + len > self.size {
+                        return None;
+                    }
+                    if inside.is_some() && self.read_str(strings + name_offset)? == name {
+                        let start = (self.base + value) as *const u8;
+                        return Some(unsafe { core::slice::from_raw_parts(start, len) });
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+
+    /// `find` for numbers, which are big-endian and one or two cells wide.
+    pub fn find_u64(&self, parent: &str, name: &str) -> Option<u64> {
+        let value = self.find(parent, name)?;
+        let cell =
+            |i: usize| u32::from_be_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]]);
+        match value.len() {
+            4 => Some(cell(0) as u64),
+            8 => Some((cell(0) as u64) << 32 | cell(4) as u64),
+            _ => None,
+        }
+    }
+
+    /// Base and size of the first range in `/memory`. Only the usual layouts
+    /// are understood, one or two cells for both.
+    pub fn memory(&self) -> Option<(u64, u64)> {
+        let reg = self.find("memory", "reg")?;
+        let cell = |i: usize| u32::from_be_bytes([reg[i], reg[i + 1], reg[i + 2], reg[i + 3]]);
+        let wide = |i: usize| (cell(i) as u64) << 32 | cell(i + 4) as u64;
+        match reg.len() {
+            8 => Some((cell(0) as u64, cell(4) as u64)),
+            16 => Some((wide(0), wide(8))),
+            _ => None,
+        }
+    }
+
+    /// Whether the first hart lists the ISA extension `ext`, e.g. `sstc`,
+    /// in either `riscv,isa-extensions` or the `riscv,isa` string.
+    pub fn has_isa_extension(&self, ext: &str) -> bool {
+        let listed = |value: &[u8], separator: u8| {
+            value
+                .split(|&b| b == separator || b == 0)
+                .any(|name| name.eq_ignore_ascii_case(ext.as_bytes()))
+        };
+        if let Some(list) = self.find("cpus", "riscv,isa-extensions") {
+            return listed(list, 0);
+        }
+        // Multi-letter extensions follow the base ISA, separated by `_`.
+        self.find("cpus", "riscv,isa")
+            .is_some_and(|isa| listed(isa, b'_'))
+    }
+}
@@ -0,0 +1,93 @@
+use alloc::vec::Vec;
+
+use crate::mm::page_table::{PTE_R, PTE_U, PTE_W, PageTable};
+use crate::mm::{
+    PAGE_SIZE, data_snapshot, frame, kernel_layout, map_kernel_image, page_round_down,
+    page_round_up,
+};
+
+/// Memory of one task.
+///
+/// Every task runs the same kernel image, so text and rodata are shared
+/// while `.data` and `.bss` are a private copy taken from the boot-time
+/// snapshot: globals a task touches in U-mode, such as its heap, are its own.
+/// The boot stacks after `.bss` are not part of it. Everything else the
+/// task needs is mapped on its own, such as its stack.
+pub struct AddressSpace {
+    table: PageTable,
+    // Frames owned by this address space, as (address, page count).
+    frames: Vec<(usize, usize)>,
+}
+
+impl AddressSpace {
+    pub fn new_user() -> Option<Self> {
+        let mut space = Self {
+            table: PageTable::new()?,
+            frames: Vec::new(),
+        };
+        map_kernel_image(&mut space.table, true)?;
+
+        let layout = kernel_layout();
+        let size = layout.bss_end - layout.data_start;
+        let globals = space.alloc_frames(size)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data_snapshot() as *const u8,
+                globals as *mut u8,
+                layout.data_end - layout.data_start,
+            );
+        }
+        space
+            .table
+            .map(layout.data_start, globals, size, PTE_R | PTE_W | PTE_U)?;
+        Some(space)
+    }
+
+    pub fn satp(&self) -> u64 {
+        self.table.satp()
+    }
+
+    pub fn page_table(&self) -> &PageTable {
+        &self.table
+    }
+
+    /// Make kernel memory, such as the task struct the trap trampoline saves
+    /// registers into, reachable from S-mode while this space is active.
+    pub fn map_kernel(&mut self, addr: usize, size: usize) -> Option<()> {
+        let start = page_round_down(addr);
+        let end = page_round_up(addr + size);
+        self.table.map(start, start, end - start, PTE_R | PTE_W)
+    }
+
+    /// Give the task read-write access to memory it owns but that is
+    /// allocated elsewhere, such as its stack.
+    pub fn map_user(&mut self, addr: usize, size: usize) -> Option<()> {
+        let start = page_round_down(addr);
+        let end = page_round_up(addr + size);
+        self.table
+            .map(start, start, end - start, PTE_R | PTE_W | PTE_U)
+    }
+
+    /// Allocate zeroed memory owned by this space and map it for the task.
+    pub fn alloc_user(&mut self, nbytes: usize) -> Option<usize> {
+        let addr = self.alloc_frames(nbytes)?;
+        self.map_user(addr, nbytes)?;
+        Some(addr)
+    }
+
+    fn alloc_frames(&mut self, nbytes: usize) -> Option<usize> {
+        let pages = nbytes.div_ceil(PAGE_SIZE).max(1);
+        self.frames.try_reserve(1).ok()?;
+        let addr = frame::alloc_contiguous(pages)?;
+        self.frames.push((addr, pages));
+        Some(addr)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for &(addr, pages) in self.frames.iter() {
+            frame::free_contiguous(addr, pages);
+        }
+    }
+}
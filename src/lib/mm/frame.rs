@@ -0,0 +1,166 @@
+use crate::mm::{PAGE_SIZE, kernel_layout, linker_symbol, page_round_up};
+use crate::mutex::Lock;
+use crate::mutex::SpinLock;
+use crate::uart::{print_integer, print_string};
+
+// QEMU virt places DRAM at 0x8000_0000.
+const DRAM_BASE: usize = 0x8000_0000;
+
+// One bit per frame, set = in use. It sits in the first frames after the
+// kernel, so it grows with DRAM instead of taking room in `.bss`.
+static mut FRAME_BITMAP: *mut u64 = core::ptr::null_mut();
+static mut BITMAP_WORDS: usize = 0;
+static mut FIRST_FRAME: usize = 0;
+static mut FRAME_COUNT: usize = 0;
+static mut FREE_FRAMES: usize = 0;
+static mut NEXT_HINT: usize = 0;
+static FRAME_LOCK: SpinLock = SpinLock::new();
+
+#[derive(Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+}
+
+// Manage every frame from the end of the kernel image up to the end of DRAM,
+// less the ones the bitmap takes.
+pub fn init() {
+    let kernel_end = page_round_up(linker_symbol!("_kernel_end"));
+    let end = kernel_layout().memory_end;
+    let words = ((end - kernel_end) / PAGE_SIZE).div_ceil(64);
+    let start = page_round_up(kernel_end + words * 8);
+    FRAME_LOCK.lock();
+    unsafe {
+        FRAME_BITMAP = kernel_end as *mut u64;
+        BITMAP_WORDS = words;
+        FIRST_FRAME = (start - DRAM_BASE) / PAGE_SIZE;
+        FRAME_COUNT = (end - start) / PAGE_SIZE;
+        FREE_FRAMES = FRAME_COUNT;
+        NEXT_HINT = 0;
+        frame_bitmap().fill(0);
+    }
+    FRAME_LOCK.unlock();
+    print_string("Frame allocator init success: ");
+    print_integer((stats().free * PAGE_SIZE / 1024) as u64);
+    print_string(" KiB free\n");
+}
+
+fn frame_bitmap() -> &'static mut [u64] {
+    unsafe { core::slice::from_raw_parts_mut(FRAME_BITMAP, BITMAP_WORDS) }
+}
+
+fn is_used(bitmap: &[u64], index: usize) -> bool {
+    bitmap[index / 64] & (1 << (index % 64)) != 0
+}
+
+fn set_used(bitmap: &mut [u64], index: usize, used: bool) {
+    if used {
+        bitmap[index / 64] |= 1 << (index % 64);
+    } else {
+        bitmap[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+fn frame_addr(index: usize) -> usize {
+    DRAM_BASE + unsafe { FIRST_FRAME + index } * PAGE_SIZE
+}
+
+// Index relative to FIRST_FRAME, or None if `addr` is not a managed frame.
+fn frame_index(addr: usize) -> Option<usize> {
+    if !addr.is_multiple_of(PAGE_SIZE) || addr < DRAM_BASE {
+        return None;
+    }
+    let index = ((addr - DRAM_BASE) / PAGE_SIZE).checked_sub(unsafe { FIRST_FRAME })?;
+    if index < unsafe { FRAME_COUNT } {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+/// Allocate one zeroed frame and return its physical address.
+pub fn alloc_frame() -> Option<usize> {
+    alloc_contiguous(1)
+}
+
+pub fn free_frame(addr: usize) {
+    free_contiguous(addr, 1);
+}
+
+/// Allocate `n` physically contiguous zeroed frames.
+pub fn alloc_contiguous(n: usize) -> Option<usize> {
+    if n == 0 {
+        return None;
+    }
+    FRAME_LOCK.lock();
+    let found = unsafe {
+        let bitmap = frame_bitmap();
+        let count = FRAME_COUNT;
+        let mut found = None;
+        if n <= FREE_FRAMES {
+            // Next-fit: start where the last search stopped, then wrap around.
+            let mut start = NEXT_HINT;
+            let mut scanned = 0;
+            while scanned < count {
+                if start + n > count {
+                    scanned += count - start;
+                    start = 0;
+                    continue;
+                }
+                match (start..start + n).find(|&i| is_used(bitmap, i)) {
+                    Some(used) => {
+                        scanned += used + 1 - start;
+                        start = used + 1;
+                    }
+                    None => {
+                        found = Some(start);
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(start) = found {
+            for i in start..start + n {
+                set_used(bitmap, i, true);
+            }
+            FREE_FRAMES -= n;
+            NEXT_HINT = start + n;
+        }
+        found
+    };
+    FRAME_LOCK.unlock();
+    let addr = frame_addr(found?);
+    unsafe {
+        core::ptr::write_bytes(addr as *mut u8, 0, n * PAGE_SIZE);
+    }
+    Some(addr)
+}
+
+pub fn free_contiguous(addr: usize, n: usize) {
+    let Some(start) = frame_index(addr) else {
+        return;
+    };
+    FRAME_LOCK.lock();
+    unsafe {
+        let bitmap = frame_bitmap();
+        for i in start..(start + n).min(FRAME_COUNT) {
+            if is_used(bitmap, i) {
+                set_used(bitmap, i, false);
+                FREE_FRAMES += 1;
+            }
+        }
+    }
+    FRAME_LOCK.unlock();
+}
+
+pub fn stats() -> FrameStats {
+    FRAME_LOCK.lock();
+    let stats = unsafe {
+        FrameStats {
+            total: FRAME_COUNT,
+            free: FREE_FRAMES,
+        }
+    };
+    FRAME_LOCK.unlock();
+    stats
+}
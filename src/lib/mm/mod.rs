@@ -0,0 +1,178 @@
+pub mod address_space;
+pub mod frame;
+pub mod page_table;
+pub mod user_ptr;
+
+use crate::csr;
+use crate::uart::print_string;
+use crate::utils::malloc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use page_table::{PTE_R, PTE_U, PTE_W, PTE_X, PageTable};
+
+pub const PAGE_SIZE: usize = 4096;
+
+const MMIO_REGIONS: [(usize, usize); 3] = [
+    (crate::uart::UART, PAGE_SIZE),
+    (crate::plic::PLIC_BASE, 0x40_0000),
+    (crate::timer::CLINT_BASE as usize, 0x1_0000),
+];
+
+static mut KERNEL_PAGE_TABLE: Option<PageTable> = None;
+static mut KERNEL_SATP: u64 = 0;
+// Frames holding `.data` as it was at boot, copied into every new task.
+static mut DATA_SNAPSHOT: usize = 0;
+// End of DRAM as the device tree has it, 0 to go by the linker script.
+static MEMORY_END: AtomicUsize = AtomicUsize::new(0);
+
+// Address of a symbol defined in linker.ld.
+macro_rules! linker_symbol {
+    ($name:literal) => {{
+        let addr: usize;
+        unsafe {
+            core::arch::asm!(
+                concat!("la {0}, ", $name),
+                out(reg) addr,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        addr
+    }};
+}
+
+pub(crate) use linker_symbol;
+
+/// Section boundaries of the kernel image, all page aligned except
+/// `data_end`.
+pub struct KernelLayout {
+    pub text_start: usize,
+    pub trampoline_start: usize,
+    pub trampoline_end: usize,
+    pub text_end: usize,
+    pub rodata_start: usize,
+    pub rodata_end: usize,
+    pub data_start: usize,
+    pub data_end: usize,
+    pub bss_end: usize,
+    pub memory_end: usize,
+}
+
+pub fn kernel_layout() -> KernelLayout {
+    KernelLayout {
+        text_start: linker_symbol!("_text_start"),
+        trampoline_start: linker_symbol!("_trampoline_start"),
+        trampoline_end: linker_symbol!("_trampoline_end"),
+        text_end: linker_symbol!("_text_end"),
+        rodata_start: linker_symbol!("_rodata_start"),
+        rodata_end: linker_symbol!("_rodata_end"),
+        data_start: linker_symbol!("_data_start"),
+        data_end: linker_symbol!("_data_end"),
+        bss_end: linker_symbol!("_bss_end"),
+        memory_end: page_round_down(memory_end()),
+    }
+}
+
+/// Use `end` as the end of DRAM, e.g. as read from the device tree, instead
+/// of what the linker script assumes. Call before `init`.
+pub fn set_memory_end(end: usize) {
+    if end > linker_symbol!("_kernel_end") {
+        MEMORY_END.store(end, Ordering::Release);
+    }
+}
+
+fn memory_end() -> usize {
+    match MEMORY_END.load(Ordering::Acquire) {
+        0 => linker_symbol!("_memory_end"),
+        end => end,
+    }
+}
+
+pub const fn page_round_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+pub const fn page_round_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+// Must run before anything writes to `.data`.
+pub fn init() {
+    frame::init();
+    snapshot_data();
+    let kernel = match build_kernel_page_table() {
+        Some(t) => t,
+        None => panic!("Failed to build the kernel page table\n"),
+    };
+    unsafe {
+        KERNEL_SATP = kernel.satp();
+        KERNEL_PAGE_TABLE = Some(kernel);
+    }
+    malloc::set_backend(malloc::HeapBackend::Frames);
+    print_string("Page table init success\n");
+}
+
+fn snapshot_data() {
+    let layout = kernel_layout();
+    let len = layout.data_end - layout.data_start;
+    let pages = len.div_ceil(PAGE_SIZE).max(1);
+    let copy = match frame::alloc_contiguous(pages) {
+        Some(addr) => addr,
+        None => panic!("Failed to snapshot .data\n"),
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(layout.data_start as *const u8, copy as *mut u8, len);
+        DATA_SNAPSHOT = copy;
+    }
+}
+
+pub fn data_snapshot() -> usize {
+    unsafe { DATA_SNAPSHOT }
+}
+
+// Map the kernel image shared between all address spaces. With `user` set,
+// text and rodata get U so tasks, which are kernel functions running in
+// U-mode, can use them; the trap trampoline always stays supervisor-only.
+pub fn map_kernel_image(table: &mut PageTable, user: bool) -> Option<()> {
+    let u = if user { PTE_U } else { 0 };
+    let layout = kernel_layout();
+    let mut map =
+        |start: usize, end: usize, flags: u64| table.map(start, start, end - start, flags);
+    map(
+        layout.text_start,
+        layout.trampoline_start,
+        PTE_R | PTE_X | u,
+    )?;
+    map(
+        layout.trampoline_start,
+        layout.trampoline_end,
+        PTE_R | PTE_X,
+    )?;
+    map(layout.trampoline_end, layout.text_end, PTE_R | PTE_X | u)?;
+    map(layout.rodata_start, layout.rodata_end, PTE_R | u)
+}
+
+// Identity map the kernel image with per-section permissions, the rest of
+// DRAM as read-write, and the MMIO regions.
+fn build_kernel_page_table() -> Option<PageTable> {
+    let layout = kernel_layout();
+    let mut table = PageTable::new()?;
+    map_kernel_image(&mut table, false)?;
+    table.map(
+        layout.data_start,
+        layout.data_start,
+        layout.memory_end - layout.data_start,
+        PTE_R | PTE_W,
+    )?;
+    for (base, size) in MMIO_REGIONS {
+        table.map(base, base, size, PTE_R | PTE_W)?;
+    }
+    Some(table)
+}
+
+pub fn kernel_satp() -> u64 {
+    unsafe { KERNEL_SATP }
+}
+
+pub fn enable_paging() {
+    csr::write_satp(kernel_satp());
+    csr::sfence_vma_all();
+}
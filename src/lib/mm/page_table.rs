@@ -0,0 +1,143 @@
+use crate::csr;
+use crate::mm::{PAGE_SIZE, frame};
+
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_G: u64 = 1 << 5;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
+
+const PTE_FLAGS_MASK: u64 = 0x3ff;
+const PTE_PPN_SHIFT: u64 = 10;
+const ENTRIES: usize = 512;
+const MEGA_PAGE_SIZE: usize = PAGE_SIZE * ENTRIES;
+
+macro_rules! vpn {
+    ($va:expr, $level:expr) => {
+        ($va >> (12 + 9 * $level)) & 0x1ff
+    };
+}
+
+fn pte_addr(pte: u64) -> usize {
+    ((pte >> PTE_PPN_SHIFT) << 12) as usize
+}
+
+fn make_pte(pa: usize, flags: u64) -> u64 {
+    ((pa as u64 >> 12) << PTE_PPN_SHIFT) | flags | PTE_V
+}
+
+fn is_leaf(pte: u64) -> bool {
+    pte & (PTE_R | PTE_W | PTE_X) != 0
+}
+
+/// An Sv39 page table, identified by the physical address of its root.
+///
+/// Tables are only touched while the frames backing them are identity
+/// mapped, which holds for the kernel table and for M-mode.
+pub struct PageTable {
+    root: usize,
+}
+
+impl PageTable {
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            root: frame::alloc_frame()?,
+        })
+    }
+
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    pub fn satp(&self) -> u64 {
+        csr::make_satp_sv39(self.root, 0)
+    }
+
+    fn entries(table: usize) -> &'static mut [u64; ENTRIES] {
+        unsafe { &mut *(table as *mut [u64; ENTRIES]) }
+    }
+
+    // Return the entry for `va` at `level` (0 = 4 KiB, 1 = 2 MiB), creating
+    // intermediate tables on the way when `alloc` is set.
+    fn walk(&mut self, va: usize, level: usize, alloc: bool) -> Option<&'static mut u64> {
+        let mut table = self.root;
+        for l in (level + 1..=2).rev() {
+            let pte = &mut Self::entries(table)[vpn!(va, l)];
+            if *pte & PTE_V == 0 {
+                if !alloc {
+                    return None;
+                }
+                *pte = make_pte(frame::alloc_frame()?, 0);
+            } else if is_leaf(*pte) {
+                // Already covered by a larger page.
+                return None;
+            }
+            table = pte_addr(*pte);
+        }
+        Some(&mut Self::entries(table)[vpn!(va, level)])
+    }
+
+    /// Map `[va, va + size)` to `[pa, pa + size)` with the given `PTE_*`
+    /// permission bits, using 2 MiB pages where alignment allows.
+    pub fn map(&mut self, va: usize, pa: usize, size: usize, flags: u64) -> Option<()> {
+        let flags = flags | PTE_A | PTE_D;
+        let mut offset = 0;
+        while offset < size {
+            let (v, p) = (va + offset, pa + offset);
+            let level = if v % MEGA_PAGE_SIZE == 0
+                && p % MEGA_PAGE_SIZE == 0
+                && size - offset >= MEGA_PAGE_SIZE
+            {
+                1
+            } else {
+                0
+            };
+            *self.walk(v, level, true)? = make_pte(p, flags);
+            offset += if level == 1 {
+                MEGA_PAGE_SIZE
+            } else {
+                PAGE_SIZE
+            };
+        }
+        Some(())
+    }
+
+    /// Translate `va` into its physical address and leaf `PTE_*` flags.
+    pub fn translate(&self, va: usize) -> Option<(usize, u64)> {
+        let mut table = self.root;
+        for level in (0..=2).rev() {
+            let pte = Self::entries(table)[vpn!(va, level)];
+            if pte & PTE_V == 0 {
+                return None;
+            }
+            if is_leaf(pte) {
+                let page_mask = (PAGE_SIZE << (9 * level)) - 1;
+                return Some((pte_addr(pte) | (va & page_mask), pte & PTE_FLAGS_MASK));
+            }
+            table = pte_addr(pte);
+        }
+        None
+    }
+}
+
+// Free the frames of every table below and including `table`. Leaf pages
+// belong to whoever mapped them.
+fn free_table(table: usize, level: usize) {
+    if level > 0 {
+        for &pte in PageTable::entries(table).iter() {
+            if pte & PTE_V != 0 && !is_leaf(pte) {
+                free_table(pte_addr(pte), level - 1);
+            }
+        }
+    }
+    frame::free_frame(table);
+}
+
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        free_table(self.root, 2);
+    }
+}
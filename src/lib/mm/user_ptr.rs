@@ -0,0 +1,178 @@
+use core::marker::PhantomData;
+use core::mem::{MaybeUninit, size_of};
+
+use crate::mm::address_space::AddressSpace;
+use crate::mm::page_table::{PTE_R, PTE_U, PTE_W};
+use crate::mm::{PAGE_SIZE, page_round_down};
+
+/// Some of the memory a task pointed the kernel at is not mapped for it, or
+/// not with the access the kernel needs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UserFault;
+
+/// A pointer a task handed to a syscall. It is only an address in the
+/// task's memory: the kernel never dereferences it directly, it copies
+/// through the task's page table after checking every page is the task's.
+#[derive(Clone, Copy)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _type: PhantomData<*mut T>,
+}
+
+/// `len` values of `T` starting at a task's pointer, see `UserPtr`.
+#[derive(Clone, Copy)]
+pub struct UserSlice<T> {
+    addr: usize,
+    len: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        Self {
+            addr: addr as usize,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// See `UserSlice::check`.
+    pub fn check(&self, space: &AddressSpace, write: bool) -> Result<(), UserFault> {
+        UserSlice::<T>::new(self.addr as u64, 1).check(space, write)
+    }
+
+    pub fn read(&self, space: &AddressSpace) -> Result<T, UserFault> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let dst = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        copy_in(space, self.addr, dst)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, space: &AddressSpace, value: T) -> Result<(), UserFault> {
+        let src =
+            unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        copy_out(space, self.addr, src)
+    }
+}
+
+impl<T: Copy> UserSlice<T> {
+    pub fn new(addr: u64, len: u64) -> Self {
+        Self {
+            addr: addr as usize,
+            len: len as usize,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The slice without its first `n` values.
+    pub fn skip(&self, n: usize) -> Self {
+        let n = n.min(self.len);
+        Self {
+            addr: self.addr.wrapping_add(n * size_of::<T>()),
+            len: self.len - n,
+            _type: PhantomData,
+        }
+    }
+
+    /// Make sure the task may read, or with `write` also write, the whole
+    /// slice, before doing something that cannot be undone if copying fails.
+    pub fn check(&self, space: &AddressSpace, write: bool) -> Result<(), UserFault> {
+        let flags = if write {
+            PTE_R | PTE_W | PTE_U
+        } else {
+            PTE_R | PTE_U
+        };
+        check_range(space, self.addr, self.byte_len()?, flags)
+    }
+
+    /// Copy as much of the slice as fits into `dst` and return how many
+    /// values that was. Nothing is copied if any of it is not readable.
+    pub fn copy_from_user(&self, space: &AddressSpace, dst: &mut [T]) -> Result<usize, UserFault> {
+        let len = self.len.min(dst.len());
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(dst.as_mut_ptr() as *mut u8, len * size_of::<T>())
+        };
+        copy_in(space, self.addr, bytes)?;
+        Ok(len)
+    }
+
+    /// Copy as much of `src` as fits into the slice and return how many
+    /// values that was. Nothing is copied if any of it is not writable.
+    pub fn copy_to_user(&self, space: &AddressSpace, src: &[T]) -> Result<usize, UserFault> {
+        let len = self.len.min(src.len());
+        let bytes =
+            unsafe { core::slice::from_raw_parts(src.as_ptr() as *const u8, len * size_of::<T>()) };
+        copy_out(space, self.addr, bytes)?;
+        Ok(len)
+    }
+
+    fn byte_len(&self) -> Result<usize, UserFault> {
+        self.len.checked_mul(size_of::<T>()).ok_or(UserFault)
+    }
+}
+
+fn copy_in(space: &AddressSpace, addr: usize, dst: &mut [u8]) -> Result<(), UserFault> {
+    check_range(space, addr, dst.len(), PTE_R | PTE_U)?;
+    for_each_page(space, addr, dst.len(), |pa, offset, n| unsafe {
+        core::ptr::copy_nonoverlapping(pa as *const u8, dst.as_mut_ptr().add(offset), n);
+    })
+}
+
+fn copy_out(space: &AddressSpace, addr: usize, src: &[u8]) -> Result<(), UserFault> {
+    check_range(space, addr, src.len(), PTE_R | PTE_W | PTE_U)?;
+    for_each_page(space, addr, src.len(), |pa, offset, n| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr().add(offset), pa as *mut u8, n);
+    })
+}
+
+// Every page of the range has to be mapped with at least `flags`.
+fn check_range(space: &AddressSpace, addr: usize, len: usize, flags: u64) -> Result<(), UserFault> {
+    let end = addr.checked_add(len).ok_or(UserFault)?;
+    let mut page = page_round_down(addr);
+    while page < end {
+        match space.page_table().translate(page) {
+            Some((_, pte_flags)) if pte_flags & flags == flags => {}
+            _ => return Err(UserFault),
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+// Call `f` with the physical address, offset into the range and length of
+// each piece of the range that lies in one page. Task memory that is
+// contiguous for the task need not be in the kernel's identity map, as with
+// its private copy of `.data` and `.bss`.
+fn for_each_page(
+    space: &AddressSpace,
+    addr: usize,
+    len: usize,
+    mut f: impl FnMut(usize, usize, usize),
+) -> Result<(), UserFault> {
+    let mut offset = 0;
+    while offset < len {
+        let va = addr + offset;
+        let n = (PAGE_SIZE - va % PAGE_SIZE).min(len - offset);
+        let (pa, _) = space.page_table().translate(va).ok_or(UserFault)?;
+        f(pa, offset, n);
+        offset += n;
+    }
+    Ok(())
+}
@@ -1,9 +1,15 @@
 #![no_std]
+extern crate alloc;
+
 pub mod csr;
+pub mod fdt;
+pub mod mm;
 pub mod mutex;
 pub mod plic;
 pub mod riscv;
+pub mod sbi;
 pub mod shell;
+pub mod smp;
 pub mod syscall;
 pub mod task;
 pub mod timer;
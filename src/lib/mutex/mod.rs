@@ -72,7 +72,7 @@ impl Lock for YieldLock {
             .is_err()
         {
             // Yield the CPU to allow other threads to run
-            sys_yield();
+            let _ = sys_yield();
         }
     }
 
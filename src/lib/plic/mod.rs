@@ -1,6 +1,6 @@
 use crate::riscv::PrivilegeMode;
 
-const PLIC_BASE: usize = 0xc000000;
+pub const PLIC_BASE: usize = 0xc000000;
 const PLIC_SOURCE_PRIORITY: *mut u32 = (PLIC_BASE + 0x000000) as *mut u32;
 const _PLIC_SOURCE_PENDING: *mut u32 = (PLIC_BASE + 0x001000) as *mut u32;
 const PLIC_SOURCE_ENABLE: *mut u32 = (PLIC_BASE + 0x002000) as *mut u32;
@@ -84,20 +84,22 @@ macro_rules! plic_set_hart_threshold {
     }};
 }
 
-pub fn plic_init() {
+// Every hart takes UART interrupts in S-mode through its own context; the
+// PLIC hands each one to a single claimer.
+pub fn plic_init(hart: usize) {
     plic_set_priority!(UART0_IRQ, 1);
-    plic_enable_irq!(0, crate::riscv::PrivilegeMode::Supervisor, UART0_IRQ);
-    plic_set_hart_threshold!(0, crate::riscv::PrivilegeMode::Supervisor, 0);
+    plic_enable_irq!(hart, crate::riscv::PrivilegeMode::Supervisor, UART0_IRQ);
+    plic_set_hart_threshold!(hart, crate::riscv::PrivilegeMode::Supervisor, 0);
 }
 
-pub fn plic_claim() -> usize {
-    let claim_addr = plic_claim_complete_addr!(0, crate::riscv::PrivilegeMode::Supervisor);
+pub fn plic_claim(hart: usize) -> usize {
+    let claim_addr = plic_claim_complete_addr!(hart, crate::riscv::PrivilegeMode::Supervisor);
     let irq_id = unsafe { core::ptr::read_volatile(claim_addr) } as usize;
     irq_id
 }
 
-pub fn plic_complete(irq_id: usize) {
-    let complete_addr = plic_claim_complete_addr!(0, crate::riscv::PrivilegeMode::Supervisor);
+pub fn plic_complete(hart: usize, irq_id: usize) {
+    let complete_addr = plic_claim_complete_addr!(hart, crate::riscv::PrivilegeMode::Supervisor);
     unsafe {
         core::ptr::write_volatile(complete_addr, irq_id as u32);
     }
@@ -13,6 +13,15 @@ impl PrivilegeMode {
             PrivilegeMode::Machine => 0b11,
         }
     }
+
+    /// The mode an MPP or SPP field holds, the reserved 0b10 read as machine.
+    pub fn from(code: u64) -> PrivilegeMode {
+        match code {
+            0b00 => PrivilegeMode::User,
+            0b01 => PrivilegeMode::Supervisor,
+            _ => PrivilegeMode::Machine,
+        }
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,127 @@
+use core::arch::asm;
+
+// Extension ids, see the RISC-V SBI specification.
+const EID_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const EID_TIME: usize = 0x5449_4D45;
+const EID_IPI: usize = 0x73_5049;
+const EID_HSM: usize = 0x48_534D;
+const EID_SRST: usize = 0x5352_5354;
+
+const FID_SET_TIMER: usize = 0;
+const FID_SEND_IPI: usize = 0;
+const FID_HART_START: usize = 0;
+const FID_SYSTEM_RESET: usize = 0;
+
+pub const RESET_TYPE_SHUTDOWN: u32 = 0;
+pub const RESET_REASON_SYSTEM_FAILURE: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Other(isize),
+}
+
+impl From<isize> for SbiError {
+    fn from(error: isize) -> Self {
+        match error {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            _ => SbiError::Other(error),
+        }
+    }
+}
+
+fn sbi_call(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+) -> Result<usize, SbiError> {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(error.into())
+    }
+}
+
+// Legacy extensions only return a value in a0 and have no function id.
+fn sbi_legacy_call(eid: usize, arg0: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => ret,
+            in("a7") eid,
+        );
+    }
+    ret
+}
+
+/// Raise a supervisor timer interrupt on this hart once `time` reaches
+/// `stime_value`. Programming a new value clears a pending one.
+pub fn set_timer(stime_value: u64) -> Result<(), SbiError> {
+    sbi_call(EID_TIME, FID_SET_TIMER, stime_value as usize, 0, 0).map(|_| ())
+}
+
+/// Raise a supervisor software interrupt on every hart whose bit is set in
+/// `hart_mask`, bit 0 being hart `hart_mask_base`.
+pub fn send_ipi(hart_mask: u64, hart_mask_base: u64) -> Result<(), SbiError> {
+    sbi_call(
+        EID_IPI,
+        FID_SEND_IPI,
+        hart_mask as usize,
+        hart_mask_base as usize,
+        0,
+    )
+    .map(|_| ())
+}
+
+/// Start a stopped hart in S-mode at physical address `start_addr`, with its
+/// hart id in a0 and `opaque` in a1 and paging off.
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+    sbi_call(EID_HSM, FID_HART_START, hart_id, start_addr, opaque).map(|_| ())
+}
+
+/// Shut down or reboot the whole system. Only returns on failure.
+pub fn system_reset(reset_type: u32, reason: u32) -> SbiError {
+    match sbi_call(
+        EID_SRST,
+        FID_SYSTEM_RESET,
+        reset_type as usize,
+        reason as usize,
+        0,
+    ) {
+        Ok(_) => SbiError::Failed,
+        Err(error) => error,
+    }
+}
+
+pub fn console_putchar(c: u8) {
+    sbi_legacy_call(EID_LEGACY_CONSOLE_PUTCHAR, c as usize);
+}
@@ -1,11 +1,21 @@
+use crate::syscall::trace::TraceMode;
 use crate::syscall::{
-    sys_alloc, sys_read, sys_sleep, sys_spawn, sys_wait, sys_write, sys_write_u64,
+    SysError, TaskInfo, sys_read, sys_spawn, sys_spawn_rt, sys_task_list, sys_trace,
+    sys_trace_dump, sys_uptime, sys_wait, sys_wait_period, sys_write, sys_write_u64,
 };
+use crate::task::DEFAULT_PRIORITY;
 use crate::utils::cstr::cstr_to_str;
 use crate::utils::list::LinkedList;
-use core::ptr::copy_nonoverlapping;
 
-pub fn shell(_argc: u64, _argv: &[&str]) {
+// Tasks `ps` lists, its buffer lives on the shell's small stack.
+const PS_MAX_TASKS: usize = 8;
+
+// Timing of the `rt` demo task, in ticks.
+const RT_DEMO_PERIOD: u64 = 100;
+const RT_DEMO_BUDGET: u64 = 10;
+const RT_DEMO_JOBS: u64 = 5;
+
+pub fn shell(_argc: u64, _argv: &[&str]) -> i32 {
     // let mut cnt = 0;
     // loop {
     //     cnt += 1;
@@ -13,20 +23,24 @@ pub fn shell(_argc: u64, _argv: &[&str]) {
     //     sys_write("\n");
     //     sys_sleep(100);
     // }
-    let buffer = [0u8; 128];
+    let mut buffer = [0u8; 128];
     let list = LinkedList::<i32>::new();
-    sys_write("====================\n");
+    print("====================\n");
     let explain = "List commands:\n\
         p   print all\n\
         ih  insert head [ih <v>]\n\
         it  insert tail [it <v>]\n\
         ph  pop head\n\
         pt  pop tail\n\
+        rt  run a periodic real-time task\n\
+        uptime  time since boot\n\
+        ps  list tasks\n\
+        trace   trace syscalls [trace <on|buf|off> [id]] or show the buffer [trace dump]\n\
         Type 'help' to see this message\n";
-    sys_write(explain);
-    sys_write("$ ");
+    print(explain);
+    print("$ ");
     loop {
-        if let Some(_read_len) = sys_read(&buffer) {
+        if let Ok(_read_len) = sys_read(&mut buffer, 0) {
             match cstr_to_str(&buffer) {
                 Ok(s) => {
                     let mut tokens = s.trim().split(' ');
@@ -39,8 +53,8 @@ pub fn shell(_argc: u64, _argv: &[&str]) {
                         }
                         "p" => {
                             for val in list.iter().unwrap() {
-                                sys_write_u64(val.get_ref().lock().value.unwrap() as u64);
-                                sys_write(" ");
+                                print_u64(val.get_ref().lock().value.unwrap() as u64);
+                                print(" ");
                             }
                         }
                         "ih" => {
@@ -48,12 +62,12 @@ pub fn shell(_argc: u64, _argv: &[&str]) {
                             match cmd2.parse::<i32>() {
                                 Ok(value) => {
                                     if list.push_front(value).is_none() {
-                                        sys_write("push_front failed");
+                                        print("push_front failed");
                                     } else {
-                                        sys_write_u64(value as u64)
+                                        print_u64(value as u64)
                                     }
                                 }
-                                Err(_) => sys_write("push_front: not a valid value"),
+                                Err(_) => print("push_front: not a valid value"),
                             }
                         }
                         "it" => {
@@ -61,60 +75,181 @@ pub fn shell(_argc: u64, _argv: &[&str]) {
                             match cmd2.parse::<i32>() {
                                 Ok(value) => {
                                     if list.push_back(value).is_none() {
-                                        sys_write("push_back failed");
+                                        print("push_back failed");
                                     } else {
-                                        sys_write_u64(value as u64)
+                                        print_u64(value as u64)
                                     }
                                 }
-                                Err(_) => sys_write("push_back: not a valid value"),
+                                Err(_) => print("push_back: not a valid value"),
                             }
                         }
                         "ph" => match list.pop_front() {
-                            Some(v) => sys_write_u64(v.get_ref().lock().value.unwrap() as u64),
-                            None => sys_write("pop_front: list empty"),
+                            Some(v) => print_u64(v.get_ref().lock().value.unwrap() as u64),
+                            None => print("pop_front: list empty"),
                         },
                         "pt" => match list.pop_back() {
-                            Some(v) => sys_write_u64(v.get_ref().lock().value.unwrap() as u64),
-                            None => sys_write("pop_back: list empty"),
+                            Some(v) => print_u64(v.get_ref().lock().value.unwrap() as u64),
+                            None => print("pop_back: list empty"),
+                        },
+                        "rt" => match sys_spawn_rt(rt_demo, s, RT_DEMO_PERIOD, RT_DEMO_BUDGET, 0) {
+                            Ok(task_id) => wait_task(task_id),
+                            Err(SysError::EBUSY) => print("Task not admitted"),
+                            Err(error) => print_error("rt", error),
                         },
-                        "help" => sys_write(explain),
-                        _ => sys_write("Unknown command"),
+                        "uptime" => {
+                            let ms = sys_uptime().unwrap_or(0);
+                            print_u64(ms / 1000);
+                            print(".");
+                            let frac = ms % 1000;
+                            if frac < 100 {
+                                print("0");
+                            }
+                            if frac < 10 {
+                                print("0");
+                            }
+                            print_u64(frac);
+                            print(" s");
+                        }
+                        "ps" => ps(),
+                        "trace" => trace(tokens.next().unwrap_or(""), tokens.next()),
+                        "help" => print(explain),
+                        _ => print("Unknown command"),
                     }
                     if func.is_some() {
-                        if let Some(ptr) = copy_to_heap(s) {
-                            if let Some(task_id) = sys_spawn(func.unwrap(), ptr, s.len()) {
-                                sys_wait(task_id as usize);
-                                sys_write("Task execute success");
-                            } else {
-                                sys_write("Task create failed");
-                            }
-                        } else {
-                            sys_write("Task create failed");
+                        // The kernel copies the arguments into the new task.
+                        match sys_spawn(func.unwrap(), s.as_ptr(), s.len(), DEFAULT_PRIORITY) {
+                            Ok(task_id) => wait_task(task_id),
+                            Err(error) => print_error("Task create failed", error),
                         }
                     }
-                    sys_write("\n$ ");
+                    print("\n$ ");
                 }
-                Err(_) => sys_write("Input Error\n$ "),
+                Err(_) => print("Input Error\n$ "),
+            }
+        }
+    }
+}
+
+fn wait_task(task_id: u64) {
+    match sys_wait(task_id as usize) {
+        Ok(0) => print("Task exited successfully"),
+        Ok(code) => {
+            print("Task exited with code ");
+            if code < 0 {
+                print("-");
             }
-        } else {
+            print_u64(code.unsigned_abs() as u64);
         }
-        sys_sleep(100);
+        Err(error) => print_error("Task not found", error),
     }
 }
 
-fn copy_to_heap(s: &str) -> Option<*const u8> {
-    let n_bytes = s.len() + 1;
-    unsafe {
-        let ptr = sys_alloc(n_bytes)?;
-        copy_nonoverlapping(s.as_ptr(), ptr, s.len());
-        *ptr.add(s.len()) = 0;
-        Some(ptr as *const u8)
+fn ps() {
+    let mut tasks = [TaskInfo::empty(); PS_MAX_TASKS];
+    let count = match sys_task_list(&mut tasks) {
+        Ok(count) => count,
+        Err(error) => {
+            print_error("ps", error);
+            return;
+        }
+    };
+    print("id\tppid\tname\tstate\tprio\tticks\tstack");
+    for info in tasks.iter().take(count) {
+        print("\n");
+        for value in [info.id, info.parent] {
+            print_u64(value);
+            print("\t");
+        }
+        print(info.name());
+        print("\t");
+        print(info.state().name());
+        print("\t");
+        for value in [info.priority, info.cpu_ticks] {
+            print_u64(value);
+            print("\t");
+        }
+        print_u64(info.stack_used);
+        print("/");
+        print_u64(info.stack_size);
+    }
+    if count > PS_MAX_TASKS {
+        print("\n... ");
+        print_u64((count - PS_MAX_TASKS) as u64);
+        print(" more");
     }
 }
 
-fn echo(_argc: u64, argv: &[&str]) {
-    if let Some(s) = argv.get(1) {
-        sys_write(s);
-        sys_write("\n");
+// Without an id the shell itself is traced, and with it every task it
+// starts afterwards.
+fn trace(arg: &str, id: Option<&str>) {
+    let mode = match arg {
+        "on" => TraceMode::Console,
+        "buf" => TraceMode::Buffer,
+        "off" => TraceMode::Off,
+        "dump" => {
+            match sys_trace_dump() {
+                Ok(count) => {
+                    print_u64(count);
+                    print(" syscalls traced");
+                }
+                Err(error) => print_error("trace", error),
+            }
+            return;
+        }
+        _ => {
+            print("trace: expected on, buf, off or dump");
+            return;
+        }
+    };
+    let id = match id.map(|id| id.parse::<u64>()) {
+        None => 0,
+        Some(Ok(id)) => id,
+        Some(Err(_)) => {
+            print("trace: not a valid task id");
+            return;
+        }
+    };
+    match sys_trace(id, mode) {
+        Ok(_) => print("ok"),
+        Err(SysError::ESRCH) => print("trace: no such task"),
+        Err(error) => print_error("trace", error),
+    }
+}
+
+// Console output the shell cannot do anything about when it fails.
+fn print(s: &str) {
+    let _ = sys_write(s);
+}
+
+fn print_u64(num: u64) {
+    let _ = sys_write_u64(num);
+}
+
+fn print_error(what: &str, error: SysError) {
+    print(what);
+    print(": ");
+    print(error.name());
+}
+
+fn echo(_argc: u64, argv: &[&str]) -> i32 {
+    match argv.get(1) {
+        Some(s) => {
+            print(s);
+            print("\n");
+            0
+        }
+        None => 1,
+    }
+}
+
+fn rt_demo(_argc: u64, _argv: &[&str]) -> i32 {
+    for job in 1..=RT_DEMO_JOBS {
+        print("rt job ");
+        print_u64(job);
+        print("\n");
+        if sys_wait_period().is_err() {
+            return 1;
+        }
     }
+    0
 }
@@ -0,0 +1,37 @@
+use crate::csr;
+use crate::timer::clear_ipi;
+#[cfg(not(feature = "sbi"))]
+use crate::timer::send_ipi;
+
+/// Harts the kernel brings up, matching `-smp 4` in the Makefile. Any hart
+/// above this stays parked.
+pub const MAX_HARTS: usize = 4;
+
+/// Wake every secondary hart waiting in `wait_for_ipi`.
+#[cfg(not(feature = "sbi"))]
+pub fn start_secondary_harts() {
+    for hart_id in 1..MAX_HARTS {
+        send_ipi(hart_id as u64);
+    }
+}
+
+/// Have the SBI firmware start every hart but `boot_hart` at `entry`. Harts
+/// the firmware does not know about are skipped.
+#[cfg(feature = "sbi")]
+pub fn start_secondary_harts(boot_hart: usize, entry: usize) {
+    for hart_id in (0..MAX_HARTS).filter(|&id| id != boot_hart) {
+        let _ = crate::sbi::hart_start(hart_id, entry, 0);
+    }
+}
+
+// Secondary harts sleep here, in M-mode, until hart 0 has set up the shared
+// kernel state and sends them a software interrupt.
+pub fn wait_for_ipi() {
+    let hart_id = csr::read_mhartid();
+    csr::write_mie(csr::read_mie() | 1 << csr::MIE_MSIE);
+    while csr::read_mip() & (1 << csr::MIP_MSIP) == 0 {
+        crate::wfi!();
+    }
+    clear_ipi(hart_id);
+    csr::write_mie(csr::read_mie() & !(1 << csr::MIE_MSIE));
+}
@@ -0,0 +1,81 @@
+use crate::mm::user_ptr::UserFault;
+
+/// Why a syscall failed. The kernel hands it back as the negated code in a0,
+/// which the `sys_*` wrappers turn into an `Err`; the numbers are the usual
+/// errno values.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u64)]
+pub enum SysError {
+    EPERM = 1,
+    ESRCH = 3,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+pub type SysResult<T> = Result<T, SysError>;
+
+// Like Linux, only the top 4095 values of a0 are errors, anything below is a
+// result, so an address or a large count is never mistaken for one.
+const MAX_ERRNO: u64 = 4095;
+
+impl SysError {
+    pub fn code(&self) -> u64 {
+        *self as u64
+    }
+
+    pub fn from(code: u64) -> SysError {
+        match code {
+            1 => SysError::EPERM,
+            3 => SysError::ESRCH,
+            10 => SysError::ECHILD,
+            11 => SysError::EAGAIN,
+            12 => SysError::ENOMEM,
+            14 => SysError::EFAULT,
+            16 => SysError::EBUSY,
+            22 => SysError::EINVAL,
+            _ => SysError::ENOSYS,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SysError::EPERM => "EPERM",
+            SysError::ESRCH => "ESRCH",
+            SysError::ECHILD => "ECHILD",
+            SysError::EAGAIN => "EAGAIN",
+            SysError::ENOMEM => "ENOMEM",
+            SysError::EFAULT => "EFAULT",
+            SysError::EBUSY => "EBUSY",
+            SysError::EINVAL => "EINVAL",
+            SysError::ENOSYS => "ENOSYS",
+        }
+    }
+}
+
+impl From<UserFault> for SysError {
+    fn from(_: UserFault) -> Self {
+        SysError::EFAULT
+    }
+}
+
+/// What the kernel puts in a0 for `result`.
+pub fn encode(result: SysResult<u64>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(error) => error.code().wrapping_neg(),
+    }
+}
+
+/// Read back what `encode` put in a0.
+pub fn decode(a0: u64) -> SysResult<u64> {
+    if a0.wrapping_neg() <= MAX_ERRNO && a0 != 0 {
+        Err(SysError::from(a0.wrapping_neg()))
+    } else {
+        Ok(a0)
+    }
+}
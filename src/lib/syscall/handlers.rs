@@ -0,0 +1,269 @@
+use crate::mm::address_space::AddressSpace;
+use crate::mm::user_ptr::{UserPtr, UserSlice};
+use crate::syscall::trace;
+use crate::syscall::trace::TraceMode;
+use crate::syscall::{
+    CLOCK_MONOTONIC, Flow, READ_NONBLOCK, SysError, SysResult, TaskInfo, Timespec,
+};
+use crate::task::scheduler;
+use crate::task::scheduler::MAX_ARGS_LEN;
+use crate::task::{PRIORITY_LEVELS, RtParams, TaskStruct};
+use crate::timer;
+use crate::timer::{NS_PER_MS, NS_PER_SEC, get_current_tick};
+use crate::uart::{UART_READ_BUFFER_SIZE, uart_read, uart_wait_line, uart_write};
+
+pub fn do_yield(_task: &mut TaskStruct) -> Flow {
+    Flow::Return(Ok(0))
+}
+
+pub fn do_exit(task: &mut TaskStruct, code: i32) -> Flow {
+    scheduler::task_exit(task, code);
+    Flow::NoReturn
+}
+
+pub fn do_sleep(task: &mut TaskStruct, ticks: u64) -> Flow {
+    let cur_tick = get_current_tick();
+    // Without a timer to wake it the task does not go to sleep at all.
+    let wakeup = timer::tick_mtime(cur_tick.saturating_add(ticks));
+    if !scheduler::sleep_until(task, wakeup) {
+        return Flow::Return(Err(SysError::ENOMEM));
+    }
+    Flow::Return(Ok(0))
+}
+
+pub fn do_write(task: &mut TaskStruct, buf: *const u8, len: usize) -> Flow {
+    let buf = UserSlice::<u8>::new(buf as u64, len as u64);
+    let result = user_space(task).and_then(|space| write_from_user(space, buf));
+    Flow::Return(result.map(|written| written as u64))
+}
+
+pub fn do_read(task: &mut TaskStruct, buf: *mut u8, len: usize, flags: u64) -> Flow {
+    let buf = UserSlice::<u8>::new(buf as u64, len as u64);
+    // Check before taking the line off the UART, so a bad buffer does not
+    // lose it.
+    let mut line = [0u8; UART_READ_BUFFER_SIZE];
+    let len = buf.len().min(line.len());
+    let checked = user_space(task).and_then(|space| buf.check(space, true).map_err(Into::into));
+    match checked.map(|_| uart_read(line.as_mut_ptr(), len)) {
+        Err(error) => Flow::Return(Err(error)),
+        Ok(Some(read_len)) => {
+            let copied = user_space(task).and_then(|space| {
+                buf.copy_to_user(space, &line[..read_len])
+                    .map_err(Into::into)
+            });
+            Flow::Return(copied.map(|_| read_len as u64))
+        }
+        // Without a line to read, block and execute the ecall again once
+        // the UART wakes us.
+        Ok(None) if len > 0 && flags & READ_NONBLOCK == 0 && uart_wait_line(task).is_some() => {
+            Flow::NoReturn
+        }
+        Ok(None) if len == 0 => Flow::Return(Ok(0)),
+        Ok(None) => Flow::Return(Err(SysError::EAGAIN)),
+    }
+}
+
+pub fn do_wait(task: &mut TaskStruct, id: u64, status: *mut i32) -> Flow {
+    // The exit code goes to `status`, if given. Check it before reaping,
+    // the code would be lost otherwise.
+    let status = UserPtr::<i32>::new(status as u64);
+    let checked = match status.addr() {
+        0 => Ok(()),
+        _ => user_space(task).and_then(|space| status.check(space, true).map_err(Into::into)),
+    };
+    if let Err(error) = checked {
+        return Flow::Return(Err(error));
+    }
+    // While the child runs the caller sleeps on it and, once woken, executes
+    // the ecall again to reap it.
+    let caller = task.id.unwrap_or(0);
+    if let Some(code) = scheduler::reap(caller, id) {
+        let written = match status.addr() {
+            0 => Ok(()),
+            _ => user_space(task).and_then(|space| status.write(space, code).map_err(Into::into)),
+        };
+        Flow::Return(written.map(|_| 0))
+    } else if scheduler::wait_for_exit(task, id) {
+        Flow::NoReturn
+    } else {
+        Flow::Return(Err(SysError::ECHILD))
+    }
+}
+
+pub fn do_spawn(
+    task: &mut TaskStruct,
+    entry: *const u8,
+    args: *const u8,
+    len: usize,
+    priority: usize,
+) -> Flow {
+    if priority >= PRIORITY_LEVELS {
+        return Flow::Return(Err(SysError::EINVAL));
+    }
+    let mut buf = [0u8; MAX_ARGS_LEN];
+    let result = copy_args(task, args, len, &mut buf).and_then(|len| {
+        scheduler::task_create(entry, buf.as_ptr(), len, Some(task), priority)
+            .ok_or(SysError::ENOMEM)
+    });
+    Flow::Return(result)
+}
+
+pub fn do_alloc(task: &mut TaskStruct, nbytes: usize) -> Flow {
+    let addr = match task.address_space.as_mut() {
+        Some(space) => space.alloc_user(nbytes),
+        None => None,
+    };
+    Flow::Return(addr.map(|a| a as u64).ok_or(SysError::ENOMEM))
+}
+
+pub fn do_set_affinity(task: &mut TaskStruct, mask: u64) -> Flow {
+    Flow::Return(scheduler::set_affinity(task, mask).ok_or(SysError::EINVAL))
+}
+
+pub fn do_set_priority(task: &mut TaskStruct, id: u64, priority: usize, time_slice: u64) -> Flow {
+    Flow::Return(if priority >= PRIORITY_LEVELS {
+        Err(SysError::EINVAL)
+    } else if scheduler::set_priority(task, id, priority, time_slice) {
+        Ok(0)
+    } else {
+        Err(SysError::ESRCH)
+    })
+}
+
+pub fn do_spawn_rt(
+    task: &mut TaskStruct,
+    entry: *const u8,
+    args: *const u8,
+    len: usize,
+    period: u64,
+    budget: u64,
+    deadline: u64,
+) -> Flow {
+    // A deadline of 0 means the end of the period.
+    let deadline = if deadline == 0 { period } else { deadline };
+    let rt = match RtParams::new(period, budget, deadline) {
+        Some(rt) => rt,
+        None => return Flow::Return(Err(SysError::EINVAL)),
+    };
+    let mut buf = [0u8; MAX_ARGS_LEN];
+    let result = copy_args(task, args, len, &mut buf).and_then(|len| {
+        // No hart has the utilization left to take it.
+        scheduler::task_create_rt(entry, buf.as_ptr(), len, Some(task), rt).ok_or(SysError::EBUSY)
+    });
+    Flow::Return(result)
+}
+
+pub fn do_wait_period(task: &mut TaskStruct) -> Flow {
+    Flow::Return(if scheduler::wait_period(task) {
+        Ok(0)
+    } else {
+        Err(SysError::EINVAL)
+    })
+}
+
+pub fn do_clock_gettime(task: &mut TaskStruct, clock: u64, ts: *mut Timespec) -> Flow {
+    if clock != CLOCK_MONOTONIC {
+        return Flow::Return(Err(SysError::EINVAL));
+    }
+    let now = timer::monotonic_ns();
+    let value = Timespec {
+        sec: now / NS_PER_SEC,
+        nsec: now % NS_PER_SEC,
+    };
+    let ts = UserPtr::<Timespec>::new(ts as u64);
+    let result = user_space(task).and_then(|space| ts.write(space, value).map_err(Into::into));
+    Flow::Return(result.map(|_| 0))
+}
+
+pub fn do_nanosleep(task: &mut TaskStruct, ns: u64) -> Flow {
+    if ns > 0 {
+        let wakeup = timer::read_time().saturating_add(timer::ns_to_mtime(ns));
+        if !scheduler::sleep_until(task, wakeup) {
+            return Flow::Return(Err(SysError::ENOMEM));
+        }
+    }
+    Flow::Return(Ok(0))
+}
+
+pub fn do_uptime(_task: &mut TaskStruct) -> Flow {
+    Flow::Return(Ok(timer::monotonic_ns() / NS_PER_MS))
+}
+
+pub fn do_trace(task: &mut TaskStruct, id: u64, mode: u64) -> Flow {
+    let mode = match TraceMode::from(mode) {
+        Some(mode) => mode,
+        None => return Flow::Return(Err(SysError::EINVAL)),
+    };
+    let previous = scheduler::set_trace(task, id, mode);
+    Flow::Return(previous.map(|m| m.code()).ok_or(SysError::ESRCH))
+}
+
+pub fn do_trace_dump(_task: &mut TaskStruct) -> Flow {
+    Flow::Return(Ok(trace::dump() as u64))
+}
+
+pub fn do_getpid(task: &mut TaskStruct) -> Flow {
+    Flow::Return(task.id.ok_or(SysError::ESRCH))
+}
+
+pub fn do_getppid(task: &mut TaskStruct) -> Flow {
+    Flow::Return(Ok(task.parent.unwrap_or(0)))
+}
+
+pub fn do_task_list(task: &mut TaskStruct, buf: *mut TaskInfo, len: usize) -> Flow {
+    let buf = UserSlice::<TaskInfo>::new(buf as u64, len as u64);
+    let space = match user_space(task) {
+        Ok(space) => space,
+        Err(error) => return Flow::Return(Err(error)),
+    };
+    if let Err(error) = buf.check(space, true) {
+        return Flow::Return(Err(error.into()));
+    }
+    // Tasks that do not fit are still counted.
+    let mut filled = 0;
+    let count = scheduler::for_each_task(|t| {
+        if filled < buf.len()
+            && buf
+                .skip(filled)
+                .copy_to_user(space, &[TaskInfo::of(t)])
+                .is_ok()
+        {
+            filled += 1;
+        }
+    });
+    Flow::Return(Ok(count as u64))
+}
+
+fn user_space(task: &TaskStruct) -> SysResult<&AddressSpace> {
+    task.address_space.as_ref().ok_or(SysError::EFAULT)
+}
+
+// The UART only takes kernel memory, so the text goes through a buffer on
+// the kernel stack.
+fn write_from_user(space: &AddressSpace, buf: UserSlice<u8>) -> SysResult<usize> {
+    buf.check(space, false)?;
+    let mut chunk = [0u8; 64];
+    let mut written = 0;
+    while written < buf.len() {
+        let n = buf.skip(written).copy_from_user(space, &mut chunk)?;
+        uart_write(chunk.as_ptr(), n);
+        written += n;
+    }
+    Ok(written)
+}
+
+// Bring spawn arguments into the kernel before the new task copies them
+// onto its stack. They have to fit there whole.
+fn copy_args(
+    task: &TaskStruct,
+    args: *const u8,
+    len: usize,
+    buf: &mut [u8; MAX_ARGS_LEN],
+) -> SysResult<usize> {
+    if len > MAX_ARGS_LEN {
+        return Err(SysError::EINVAL);
+    }
+    let user_args = UserSlice::<u8>::new(args as u64, len as u64);
+    let space = user_space(task)?;
+    Ok(user_args.copy_from_user(space, buf)?)
+}
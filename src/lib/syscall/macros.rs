@@ -0,0 +1,186 @@
+use crate::syscall::error::{SysResult, decode};
+
+/// How a traced argument is shown.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ArgFormat {
+    Unsigned,
+    Signed,
+    Hex,
+}
+
+/// A value that travels in one argument register.
+pub trait SyscallArg {
+    const FORMAT: ArgFormat = ArgFormat::Unsigned;
+
+    fn into_reg(self) -> u64;
+    fn from_reg(reg: u64) -> Self;
+}
+
+impl SyscallArg for u64 {
+    fn into_reg(self) -> u64 {
+        self
+    }
+
+    fn from_reg(reg: u64) -> Self {
+        reg
+    }
+}
+
+impl SyscallArg for usize {
+    fn into_reg(self) -> u64 {
+        self as u64
+    }
+
+    fn from_reg(reg: u64) -> Self {
+        reg as usize
+    }
+}
+
+impl SyscallArg for i32 {
+    const FORMAT: ArgFormat = ArgFormat::Signed;
+
+    fn into_reg(self) -> u64 {
+        self as i64 as u64
+    }
+
+    fn from_reg(reg: u64) -> Self {
+        reg as i32
+    }
+}
+
+// The kernel never dereferences these, see `UserPtr`.
+impl<T> SyscallArg for *const T {
+    const FORMAT: ArgFormat = ArgFormat::Hex;
+
+    fn into_reg(self) -> u64 {
+        self as u64
+    }
+
+    fn from_reg(reg: u64) -> Self {
+        reg as *const T
+    }
+}
+
+impl<T> SyscallArg for *mut T {
+    const FORMAT: ArgFormat = ArgFormat::Hex;
+
+    fn into_reg(self) -> u64 {
+        self as u64
+    }
+
+    fn from_reg(reg: u64) -> Self {
+        reg as *mut T
+    }
+}
+
+/// Make the syscall `code` with `args` in a0 to a5, unused ones zeroed.
+#[inline(always)]
+pub fn ecall<const N: usize>(code: u64, args: [u64; N]) -> SysResult<u64> {
+    const { assert!(N <= 6, "a syscall takes at most six arguments") };
+    let mut regs = [0u64; 6];
+    regs[..N].copy_from_slice(&args);
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") regs[0] => ret,
+            inlateout("a1") regs[1] => _,
+            inlateout("a2") regs[2] => _,
+            inlateout("a3") regs[3] => _,
+            inlateout("a4") regs[4] => _,
+            inlateout("a5") regs[5] => _,
+            in("a7") code,
+        );
+    }
+    decode(ret)
+}
+
+/// Define every syscall once: its number, the kernel handler and the typed
+/// arguments both sides agree on.
+///
+/// ```text
+/// Write = 3, fn write(buf: *const u8, len: usize) => do_write;
+/// ```
+///
+/// makes `Syscall::Write`, a dispatch arm that calls
+/// `do_write(task, buf, len) -> Flow` with the arguments taken from a0 and
+/// a1, and a stub `raw::write(buf, len) -> SysResult<u64>` for tasks.
+macro_rules! syscalls {
+    ($(
+        $(#[$doc:meta])*
+        $name:ident = $code:literal, fn $stub:ident($($arg:ident: $ty:ty),* $(,)?) => $handler:ident;
+    )*) => {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        #[repr(u64)]
+        pub enum Syscall {
+            $($name = $code,)*
+            Unknown,
+        }
+
+        impl Syscall {
+            pub fn code(&self) -> u64 {
+                *self as u64
+            }
+
+            pub fn from(code: u64) -> Syscall {
+                match code {
+                    $($code => Syscall::$name,)*
+                    _ => Syscall::Unknown,
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Syscall::$name => stringify!($name),)*
+                    Syscall::Unknown => "Unknown",
+                }
+            }
+
+            /// Names and formats of the arguments, in register order.
+            pub fn args(&self) -> &'static [(&'static str, $crate::syscall::macros::ArgFormat)] {
+                match self {
+                    $(Syscall::$name => &[$((
+                        stringify!($arg),
+                        <$ty as $crate::syscall::macros::SyscallArg>::FORMAT,
+                    )),*],)*
+                    Syscall::Unknown => &[],
+                }
+            }
+        }
+
+        fn dispatch(syscall: Syscall, task: &mut $crate::task::TaskStruct) -> Flow {
+            match syscall {
+                $(Syscall::$name => {
+                    #[allow(unused_mut, unused_variables)]
+                    let mut regs = task.a.into_iter();
+                    $(
+                        let $arg = <$ty as $crate::syscall::macros::SyscallArg>::from_reg(
+                            regs.next().unwrap_or(0),
+                        );
+                    )*
+                    $handler(task, $($arg),*)
+                })*
+                Syscall::Unknown => Flow::Return(Err(SysError::ENOSYS)),
+            }
+        }
+
+        /// Syscall stubs, taking the arguments as the kernel sees them.
+        pub mod raw {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $(
+                $(#[$doc])*
+                #[inline(never)]
+                pub fn $stub($($arg: $ty),*) -> $crate::syscall::SysResult<u64> {
+                    $crate::syscall::macros::ecall(
+                        super::Syscall::$name.code(),
+                        [$($crate::syscall::macros::SyscallArg::into_reg($arg)),*],
+                    )
+                }
+            )*
+        }
+    };
+}
+
+pub(crate) use syscalls;
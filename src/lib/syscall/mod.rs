@@ -1,251 +1,289 @@
-use crate::task::scheduler;
-use crate::task::{TaskState, TaskStruct};
-use crate::timer::get_current_tick;
-use crate::uart::{uart_read, uart_write};
-use crate::utils::cstr::u64_to_str;
-use crate::utils::malloc;
+pub mod error;
+mod handlers;
+pub(crate) mod macros;
+pub mod trace;
+
+pub use error::{SysError, SysResult};
+
+use crate::task::{TASK_NAME_LEN, TaskState, TaskStruct};
+use crate::utils::cstr::{cstr_to_str, u64_to_str};
+use error::encode;
+use handlers::*;
+use macros::syscalls;
+use trace::TraceMode;
+
+/// `sys_read` flag: return right away when no line is buffered yet.
+pub const READ_NONBLOCK: u64 = 1 << 0;
+
+/// Time since boot that never jumps, the only clock there is so far.
+pub const CLOCK_MONOTONIC: u64 = 1;
 
 #[derive(Clone, Copy)]
-#[repr(u64)]
-pub enum Syscall {
-    Yield = 0,
-    Exit = 1,
-    Sleep = 2,
-    Write = 3,
-    Read = 4,
-    Wait = 5,
-    Spawn = 6,
-    Alloc = 7,
-    Unknown,
-}
-
-impl Syscall {
-    pub fn code(&self) -> u64 {
-        self.clone() as u64
+#[repr(C)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u64,
+}
+
+/// One task as `sys_task_list` reports it.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct TaskInfo {
+    pub id: u64,
+    /// 0 for a task the kernel started.
+    pub parent: u64,
+    pub name: [u8; TASK_NAME_LEN],
+    /// See `TaskState::code`.
+    pub state: u64,
+    pub priority: u64,
+    /// Timer ticks the task ran for.
+    pub cpu_ticks: u64,
+    /// Bytes of the stack in use when the task last trapped, out of
+    /// `stack_size`.
+    pub stack_used: u64,
+    pub stack_size: u64,
+}
+
+impl TaskInfo {
+    pub const fn empty() -> Self {
+        Self {
+            id: 0,
+            parent: 0,
+            name: [0; TASK_NAME_LEN],
+            state: 0,
+            priority: 0,
+            cpu_ticks: 0,
+            stack_used: 0,
+            stack_size: 0,
+        }
     }
 
-    pub fn from(code: u64) -> Syscall {
-        match code {
-            0 => Syscall::Yield,
-            1 => Syscall::Exit,
-            2 => Syscall::Sleep,
-            3 => Syscall::Write,
-            4 => Syscall::Read,
-            5 => Syscall::Wait,
-            6 => Syscall::Spawn,
-            7 => Syscall::Alloc,
-            _ => Syscall::Unknown,
+    pub fn of(task: &TaskStruct) -> Self {
+        // The scheduler keeps a task on a hart as ready.
+        let state = match task.state {
+            TaskState::Ready if task.on_cpu => TaskState::Running,
+            state => state,
+        };
+        Self {
+            id: task.id.unwrap_or(0),
+            parent: task.parent.unwrap_or(0),
+            name: task.name,
+            state: state.code(),
+            priority: task.priority as u64,
+            cpu_ticks: task.cpu_ticks,
+            stack_used: task.stack_used() as u64,
+            stack_size: task.stack_size() as u64,
         }
     }
+
+    pub fn name(&self) -> &str {
+        cstr_to_str(&self.name).unwrap_or("?")
+    }
+
+    pub fn state(&self) -> TaskState {
+        TaskState::from(self.state)
+    }
+}
+
+/// What a handler did with the calling task.
+pub enum Flow {
+    /// Hand back a value or an error in a0 and continue after the ecall.
+    Return(SysResult<u64>),
+    /// The task does not continue after the ecall: it exited, or it blocked
+    /// and executes the ecall again once woken.
+    NoReturn,
+}
+
+syscalls! {
+    Yield = 0, fn sched_yield() => do_yield;
+    Exit = 1, fn exit(code: i32) => do_exit;
+    Sleep = 2, fn sleep(ticks: u64) => do_sleep;
+    Write = 3, fn write(buf: *const u8, len: usize) => do_write;
+    Read = 4, fn read(buf: *mut u8, len: usize, flags: u64) => do_read;
+    Wait = 5, fn wait(id: u64, status: *mut i32) => do_wait;
+    Spawn = 6, fn spawn(entry: *const u8, args: *const u8, len: usize, priority: usize) => do_spawn;
+    Alloc = 7, fn alloc(nbytes: usize) => do_alloc;
+    SetAffinity = 8, fn set_affinity(mask: u64) => do_set_affinity;
+    SetPriority = 9, fn set_priority(id: u64, priority: usize, time_slice: u64) => do_set_priority;
+    SpawnRt = 10, fn spawn_rt(
+        entry: *const u8,
+        args: *const u8,
+        len: usize,
+        period: u64,
+        budget: u64,
+        deadline: u64,
+    ) => do_spawn_rt;
+    WaitPeriod = 11, fn wait_period() => do_wait_period;
+    ClockGettime = 12, fn clock_gettime(clock: u64, ts: *mut Timespec) => do_clock_gettime;
+    Nanosleep = 13, fn nanosleep(ns: u64) => do_nanosleep;
+    Uptime = 14, fn uptime() => do_uptime;
+    Trace = 15, fn trace(id: u64, mode: u64) => do_trace;
+    TraceDump = 16, fn trace_dump() => do_trace_dump;
+    GetPid = 17, fn getpid() => do_getpid;
+    GetPpid = 18, fn getppid() => do_getppid;
+    TaskList = 19, fn task_list(buf: *mut TaskInfo, len: usize) => do_task_list;
 }
 
+/// Run the syscall in a7 for `task`. Unknown numbers fail with `ENOSYS`,
+/// likely a task built against a newer kernel, which can cope. Tasks with
+/// tracing on have the call logged once it is done.
 pub fn syscall_handler(task: &mut TaskStruct) {
-    let syscall = Syscall::from(task.a[7]);
-    match syscall {
-        Syscall::Yield => {
-            task.state = TaskState::Ready;
+    let code = task.a[7];
+    let mut args = [0; 6];
+    args.copy_from_slice(&task.a[..6]);
+    task.state = TaskState::Ready;
+    let ret = match dispatch(Syscall::from(code), task) {
+        Flow::Return(result) => {
             task.xepc += 4;
+            task.a[0] = encode(result);
+            Some(task.a[0])
         }
-        Syscall::Exit => {
-            // TODO: exit code
-            task.state = TaskState::None;
-        }
-        Syscall::Sleep => {
-            task.state = TaskState::Sleeping;
-            task.xepc += 4;
-            let cur_tick = get_current_tick();
-            let sleep_ticks = task.a[0];
-            task.sleep_until = Some(cur_tick + sleep_ticks);
-        }
-        Syscall::Write => {
-            task.state = TaskState::Ready;
-            task.xepc += 4;
-            let ptr = task.a[0] as *const u8;
-            let len = task.a[1] as usize;
-            uart_write(ptr, len);
-        }
-        Syscall::Read => {
-            task.state = TaskState::Ready;
-            task.xepc += 4;
-            let buf = task.a[0] as *mut u8;
-            let len = task.a[1] as usize;
-            let read_len = uart_read(buf, len).unwrap_or(0);
-            task.a[0] = read_len as u64;
-        }
-        Syscall::Wait => {
-            task.state = TaskState::Ready;
-            let wait_id = task.a[0];
-            if scheduler::get_task_state(wait_id) == TaskState::None {
-                task.xepc += 4;
-            }
-        }
-        Syscall::Spawn => {
-            task.state = TaskState::Ready;
-            task.xepc += 4;
-            let task_ptr = task.a[0] as *const u8;
-            let args = task.a[1] as *const u8;
-            let len = task.a[2] as usize;
-            task.a[0] = scheduler::task_create(task_ptr, args, len).unwrap_or(0);
-        }
-        Syscall::Alloc => {
-            task.state = TaskState::Ready;
-            task.xepc += 4;
-            let nbytes = task.a[0] as usize;
-            task.a[0] = unsafe { malloc::malloc(nbytes).unwrap_or(core::ptr::null_mut()) as u64 };
-        }
-        Syscall::Unknown => panic!("Unknown syscall code: {}", task.a[7]),
+        Flow::NoReturn => None,
+    };
+    if task.trace != TraceMode::Off {
+        trace::record(task.trace, task.id.unwrap_or(0), code, args, ret);
     }
 }
 
-#[inline(never)]
-pub fn sys_yield() {
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {}",
-            "ecall",
-            in(reg) Syscall::Yield.code(),
-        );
-    }
+pub fn sys_yield() -> SysResult<()> {
+    raw::sched_yield().map(|_| ())
 }
 
-#[inline(never)]
-pub fn sys_exit(id: u64) {
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {}",
-            "mv a0, {}",
-            "ecall",
-            in(reg) Syscall::Exit.code(),
-            in(reg) id,
-        );
-    }
+pub fn sys_exit(code: i32) {
+    let _ = raw::exit(code);
 }
 
-#[inline(never)]
-pub fn sys_sleep(ticks: u64) {
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {}",
-            "mv a0, {}",
-            "ecall",
-            in(reg) Syscall::Sleep.code(),
-            in(reg) ticks,
-        );
-    }
+/// Sleep for `ticks` scheduler ticks. Fails with `ENOMEM` if no timer could
+/// be set up to wake the caller.
+pub fn sys_sleep(ticks: u64) -> SysResult<()> {
+    raw::sleep(ticks).map(|_| ())
 }
 
-#[inline(never)]
-pub fn sys_write(s: &str) {
-    let ptr = core::hint::black_box(s.as_ptr());
-    let len = core::hint::black_box(s.len());
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {syscall_code}",
-            "mv a0, {ptr}",
-            "mv a1, {len}",
-            "ecall",
-            syscall_code = in(reg) Syscall::Write.code(),
-            ptr = in(reg) ptr,
-            len = in(reg) len,
-        );
-    }
+/// Queue `s` for the console and return how many bytes that was.
+pub fn sys_write(s: &str) -> SysResult<usize> {
+    raw::write(s.as_ptr(), s.len()).map(|written| written as usize)
 }
 
-#[inline(never)]
-pub fn sys_write_u64(num: u64) {
+pub fn sys_write_u64(num: u64) -> SysResult<usize> {
     let mut buffer = [0; 20];
     match u64_to_str(num, &mut buffer) {
-        Ok(num) => unsafe {
-            let ptr = core::hint::black_box(num.as_ptr());
-            let len = core::hint::black_box(num.len());
-            core::arch::asm!(
-                "mv a7, {syscall_code}",
-                "mv a0, {ptr}",
-                "mv a1, {len}",
-                "ecall",
-                syscall_code = in(reg) Syscall::Write.code(),
-                ptr = in(reg) ptr,
-                len = in(reg) len,
-            );
-        },
-        Err(_) => {
-            //
-        }
+        Ok(num) => sys_write(num),
+        Err(_) => Err(SysError::EINVAL),
     }
 }
 
-#[inline(never)]
-pub fn sys_read(buf: &[u8]) -> Option<u64> {
-    let mut read_len: u64;
-    let ptr = core::hint::black_box(buf.as_ptr());
-    let len = core::hint::black_box(buf.len());
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {syscall_code}",
-            "mv a0, {ptr}",
-            "mv a1, {len}",
-            "ecall",
-            "mv {read_len}, a0",
-            syscall_code = in(reg) Syscall::Read.code(),
-            ptr = in(reg) ptr,
-            len = in(reg) len,
-            read_len = out(reg) read_len,
-        );
-    }
-    if read_len == 0 { None } else { Some(read_len) }
-}
-
-#[inline(never)]
-pub fn sys_wait(pid: usize) {
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {syscall_code}",
-            "mv a0, {pid}",
-            "ecall",
-            syscall_code = in(reg) Syscall::Wait.code(),
-            pid = in(reg) pid,
-        );
-    }
+/// Read a line into `buf`, NUL terminated, and return its length with the
+/// terminator. With `READ_NONBLOCK` this fails with `EAGAIN` instead of
+/// waiting for a line.
+pub fn sys_read(buf: &mut [u8], flags: u64) -> SysResult<usize> {
+    raw::read(buf.as_mut_ptr(), buf.len(), flags).map(|read_len| read_len as usize)
 }
 
-#[inline(never)]
-pub fn sys_spawn(task: fn(argc: u64, argv: &[&str]), args: *const u8, len: usize) -> Option<u64> {
-    let mut id;
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {syscall_code}",
-            "mv a0, {task}",
-            "mv a1, {args}",
-            "mv a2, {len}",
-            "ecall",
-            "mv {id}, a0",
-            syscall_code = in(reg) Syscall::Spawn.code(),
-            task = in(reg) task,
-            args = in(reg) args,
-            len = in(reg) len,
-            id = out(reg) id,
-        );
-    }
-    if id == 0 { None } else { Some(id) }
-}
-
-#[inline(never)]
-pub fn sys_alloc(nbytes: usize) -> Option<*mut u8> {
-    let mut ptr: u64;
-    unsafe {
-        core::arch::asm!(
-            "mv a7, {syscall_code}",
-            "mv a0, {nbytes}",
-            "ecall",
-            "mv {ptr}, a0",
-            syscall_code = in(reg) Syscall::Alloc.code(),
-            nbytes = in(reg) nbytes,
-            ptr = out(reg) ptr,
-        );
-    }
-    if ptr == (core::ptr::null_mut::<u8>() as u64) {
-        None
-    } else {
-        Some(ptr as *mut u8)
-    }
+/// Wait for the child `pid` to exit and return its exit code. Fails with
+/// `ECHILD` if the caller has no such child.
+pub fn sys_wait(pid: usize) -> SysResult<i32> {
+    let mut code = 0;
+    raw::wait(pid as u64, &mut code).map(|_| code)
+}
+
+/// Spawn a task running `task` with the `len` bytes at `args` as its
+/// arguments. Fails with `EINVAL` if they are longer than `MAX_ARGS_LEN`.
+pub fn sys_spawn(
+    task: fn(argc: u64, argv: &[&str]) -> i32,
+    args: *const u8,
+    len: usize,
+    priority: usize,
+) -> SysResult<u64> {
+    raw::spawn(task as *const u8, args, len, priority)
+}
+
+pub fn sys_alloc(nbytes: usize) -> SysResult<*mut u8> {
+    raw::alloc(nbytes).map(|ptr| ptr as *mut u8)
+}
+
+/// Limit the calling task to the harts whose bits are set in `mask` and
+/// return the previous mask. Fails with `EINVAL` if `mask` names no hart or
+/// the task is real-time.
+pub fn sys_set_affinity(mask: u64) -> SysResult<u64> {
+    raw::set_affinity(mask)
+}
+
+/// Move the task `id` (0 for the caller) to `priority` with turns of
+/// `time_slice` ticks, 0 picking the default for that priority.
+pub fn sys_setpriority(id: u64, priority: usize, time_slice: u64) -> SysResult<()> {
+    raw::set_priority(id, priority, time_slice).map(|_| ())
+}
+
+/// Spawn a real-time task that gets `budget` ticks of every `period`,
+/// finishing each job within `deadline` ticks (0 for the whole period).
+/// Fails with `EINVAL` if the timing is invalid, the period is longer than
+/// `MAX_RT_PERIOD` or `args` is longer than `MAX_ARGS_LEN`, or `EBUSY` if no
+/// hart has room for it.
+pub fn sys_spawn_rt(
+    task: fn(argc: u64, argv: &[&str]) -> i32,
+    args: &str,
+    period: u64,
+    budget: u64,
+    deadline: u64,
+) -> SysResult<u64> {
+    raw::spawn_rt(
+        task as *const u8,
+        args.as_ptr(),
+        args.len(),
+        period,
+        budget,
+        deadline,
+    )
+}
+
+/// Finish the current job of a real-time task and sleep until the next
+/// period starts. Fails with `EINVAL` for a task that is not real-time.
+pub fn sys_wait_period() -> SysResult<()> {
+    raw::wait_period().map(|_| ())
+}
+
+/// Read `clock`. Fails with `EINVAL` if there is no such clock.
+pub fn sys_clock_gettime(clock: u64) -> SysResult<Timespec> {
+    let mut ts = Timespec { sec: 0, nsec: 0 };
+    raw::clock_gettime(clock, &mut ts).map(|_| ts)
+}
+
+/// Sleep for at least `ns` nanoseconds, not rounded to scheduler ticks.
+/// Fails with `ENOMEM` if no timer could be set up to wake the caller.
+pub fn sys_nanosleep(ns: u64) -> SysResult<()> {
+    raw::nanosleep(ns).map(|_| ())
+}
+
+/// Milliseconds since boot.
+pub fn sys_uptime() -> SysResult<u64> {
+    raw::uptime()
+}
+
+/// Set how the syscalls of the task `id` (0 for the caller) are traced and
+/// return the previous mode. Tasks it spawns from then on start with the
+/// same mode. Fails with `ESRCH` if there is no such task.
+pub fn sys_trace(id: u64, mode: TraceMode) -> SysResult<TraceMode> {
+    raw::trace(id, mode.code()).map(|previous| TraceMode::from(previous).unwrap_or(TraceMode::Off))
+}
+
+/// Print the syscalls traced into the buffer, oldest first, and empty it.
+/// Returns how many there were.
+pub fn sys_trace_dump() -> SysResult<u64> {
+    raw::trace_dump()
+}
+
+/// Id of the calling task.
+pub fn sys_getpid() -> SysResult<u64> {
+    raw::getpid()
+}
+
+/// Id of the task that spawned the caller, 0 if the kernel started it.
+pub fn sys_getppid() -> SysResult<u64> {
+    raw::getppid()
+}
+
+/// Describe every task not reaped yet in `buf` and return how many tasks
+/// there are, which may be more than fit.
+pub fn sys_task_list(buf: &mut [TaskInfo]) -> SysResult<usize> {
+    raw::task_list(buf.as_mut_ptr(), buf.len()).map(|count| count as usize)
 }
@@ -0,0 +1,158 @@
+use crate::mutex::Lock;
+use crate::mutex::SpinLock;
+use crate::syscall::Syscall;
+use crate::syscall::error::decode;
+use crate::syscall::macros::ArgFormat;
+use crate::uart::{print_char, print_hex, print_integer, print_string};
+
+/// What `syscall_handler` does with the syscalls of a task.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u64)]
+pub enum TraceMode {
+    Off = 0,
+    /// Log every syscall to the console as it returns.
+    Console = 1,
+    /// Keep the last `TRACE_BUFFER_SIZE` syscalls for `sys_trace_dump`.
+    Buffer = 2,
+}
+
+impl TraceMode {
+    pub fn code(&self) -> u64 {
+        *self as u64
+    }
+
+    pub fn from(code: u64) -> Option<TraceMode> {
+        match code {
+            0 => Some(TraceMode::Off),
+            1 => Some(TraceMode::Console),
+            2 => Some(TraceMode::Buffer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TraceRecord {
+    task: u64,
+    code: u64,
+    args: [u64; 6],
+    // What went back in a0, `None` if the task did not continue after the
+    // ecall.
+    ret: Option<u64>,
+}
+
+impl TraceRecord {
+    const fn empty() -> Self {
+        Self {
+            task: 0,
+            code: 0,
+            args: [0; 6],
+            ret: None,
+        }
+    }
+}
+
+const TRACE_BUFFER_SIZE: usize = 64;
+static mut TRACE_BUFFER: [TraceRecord; TRACE_BUFFER_SIZE] =
+    [TraceRecord::empty(); TRACE_BUFFER_SIZE];
+static mut TRACE_HEAD: usize = 0;
+static mut TRACE_LEN: usize = 0;
+static TRACE_LOCK: SpinLock = SpinLock::new();
+// Keeps the lines of harts tracing at the same time apart.
+static TRACE_PRINT_LOCK: SpinLock = SpinLock::new();
+
+/// Trace a syscall of `task` made with `args`, following `mode`. Once the
+/// buffer is full the oldest record is dropped.
+pub fn record(mode: TraceMode, task: u64, code: u64, args: [u64; 6], ret: Option<u64>) {
+    let record = TraceRecord {
+        task,
+        code,
+        args,
+        ret,
+    };
+    match mode {
+        TraceMode::Off => {}
+        TraceMode::Console => {
+            TRACE_PRINT_LOCK.lock();
+            print_record(&record);
+            TRACE_PRINT_LOCK.unlock();
+        }
+        TraceMode::Buffer => {
+            TRACE_LOCK.lock();
+            unsafe {
+                let tail = (TRACE_HEAD + TRACE_LEN) % TRACE_BUFFER_SIZE;
+                TRACE_BUFFER[tail] = record;
+                if TRACE_LEN == TRACE_BUFFER_SIZE {
+                    TRACE_HEAD = (TRACE_HEAD + 1) % TRACE_BUFFER_SIZE;
+                } else {
+                    TRACE_LEN += 1;
+                }
+            }
+            TRACE_LOCK.unlock();
+        }
+    }
+}
+
+/// Print the buffered records, oldest first, and empty the buffer. Returns
+/// how many there were.
+pub fn dump() -> usize {
+    TRACE_LOCK.lock();
+    TRACE_PRINT_LOCK.lock();
+    let len = unsafe {
+        for i in 0..TRACE_LEN {
+            let record = TRACE_BUFFER[(TRACE_HEAD + i) % TRACE_BUFFER_SIZE];
+            print_record(&record);
+        }
+        let len = TRACE_LEN;
+        TRACE_HEAD = 0;
+        TRACE_LEN = 0;
+        len
+    };
+    TRACE_PRINT_LOCK.unlock();
+    TRACE_LOCK.unlock();
+    len
+}
+
+// One line like `[trace] task 3: Write(buf=0x..., len=6) = 6`, with `?` for
+// the result of a syscall that did not return.
+fn print_record(record: &TraceRecord) {
+    let syscall = Syscall::from(record.code);
+    print_string("[trace] task ");
+    print_integer(record.task);
+    print_string(": ");
+    print_string(syscall.name());
+    if syscall == Syscall::Unknown {
+        print_char(' ');
+        print_integer(record.code);
+    }
+    print_char('(');
+    for (i, ((name, format), value)) in syscall.args().iter().zip(record.args).enumerate() {
+        if i > 0 {
+            print_string(", ");
+        }
+        print_string(name);
+        print_char('=');
+        match format {
+            ArgFormat::Unsigned => print_integer(value),
+            ArgFormat::Hex => print_hex(value),
+            ArgFormat::Signed => print_signed(value as i64),
+        }
+    }
+    print_string(") = ");
+    match record.ret.map(decode) {
+        Some(Ok(value)) => print_integer(value),
+        Some(Err(error)) => {
+            print_char('-');
+            print_string(error.name());
+        }
+        None => print_char('?'),
+    }
+    print_char('\n');
+}
+
+fn print_signed(value: i64) {
+    if value < 0 {
+        print_char('-');
+    }
+    print_integer(value.unsigned_abs());
+}
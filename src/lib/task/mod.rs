@@ -1,13 +1,99 @@
 use core::{mem::offset_of, ptr::NonNull};
 
-use crate::utils::malloc::{free, malloc};
+use crate::mm::address_space::AddressSpace;
+use crate::mm::{PAGE_SIZE, frame};
+use crate::syscall::trace::TraceMode;
 use crate::utils::rc::Arc;
+use wait_queue::WaitQueue;
 
+pub mod policy;
 pub mod scheduler;
 pub mod test_task;
+pub mod wait_queue;
 
 const USER_STACK_ALIGNMENT: usize = 16;
 const USER_STACK_SIZE: usize = 4096;
+const KERNEL_STACK_SIZE: usize = 4 * 4096;
+
+/// Longest task name kept, the rest of the first spawn argument is dropped.
+pub const TASK_NAME_LEN: usize = 16;
+
+/// Affinity mask letting a task run on every hart.
+pub const ALL_HARTS: u64 = (1 << crate::smp::MAX_HARTS) - 1;
+
+/// Priority 0 runs first; a level only gets the hart while every level
+/// above it has nothing ready.
+pub const PRIORITY_LEVELS: usize = 4;
+pub const DEFAULT_PRIORITY: usize = 2;
+
+/// Timer ticks a task of `priority` runs before the next one gets a turn.
+/// Urgent levels switch often to stay responsive, background ones run
+/// longer between switches.
+pub const fn default_time_slice(priority: usize) -> u64 {
+    1 << priority
+}
+
+/// Longest period a real-time task may ask for, in ticks. Keeps release
+/// and deadline arithmetic far from overflowing.
+pub const MAX_RT_PERIOD: u64 = 1 << 32;
+
+/// Timing of a periodic real-time task, in timer ticks. Every `period` a
+/// new job is released, which may run for `budget` ticks and has to finish
+/// within `deadline` ticks of its release.
+#[derive(Clone, Copy)]
+pub struct RtParams {
+    pub period: u64,
+    pub budget: u64,
+    pub deadline: u64,
+    // Release of the current job and what is left of its budget.
+    pub release: u64,
+    pub budget_left: u64,
+    // Whether the current job was already reported late, and how many were.
+    pub missed: bool,
+    pub misses: u64,
+    // Jobs cut off for using up their budget.
+    pub overruns: u64,
+}
+
+impl RtParams {
+    pub fn new(period: u64, budget: u64, deadline: u64) -> Option<Self> {
+        if budget == 0 || budget > deadline || deadline > period || period > MAX_RT_PERIOD {
+            return None;
+        }
+        Some(Self {
+            period,
+            budget,
+            deadline,
+            release: 0,
+            budget_left: budget,
+            missed: false,
+            misses: 0,
+            overruns: 0,
+        })
+    }
+
+    pub fn abs_deadline(&self) -> u64 {
+        self.release + self.deadline
+    }
+
+    /// Start the job released at `release` with a full budget.
+    pub fn start_job(&mut self, release: u64) {
+        self.release = release;
+        self.budget_left = self.budget;
+        self.missed = false;
+    }
+
+    /// Release of the job after the current one, or of the one already
+    /// running at `now` if whole periods went by.
+    pub fn next_release(&self, now: u64) -> u64 {
+        let next = self.release + self.period;
+        if next > now {
+            next
+        } else {
+            now - (now - self.release) % self.period
+        }
+    }
+}
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum TaskState {
@@ -16,6 +102,43 @@ pub enum TaskState {
     Running,
     Sleeping,
     Blocked,
+    // Exited, keeping its exit code until a `sys_wait` reaps it.
+    Zombie,
+}
+
+impl TaskState {
+    pub fn code(&self) -> u64 {
+        match self {
+            TaskState::None => 0,
+            TaskState::Ready => 1,
+            TaskState::Running => 2,
+            TaskState::Sleeping => 3,
+            TaskState::Blocked => 4,
+            TaskState::Zombie => 5,
+        }
+    }
+
+    pub fn from(code: u64) -> TaskState {
+        match code {
+            1 => TaskState::Ready,
+            2 => TaskState::Running,
+            3 => TaskState::Sleeping,
+            4 => TaskState::Blocked,
+            5 => TaskState::Zombie,
+            _ => TaskState::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskState::None => "none",
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Sleeping => "sleeping",
+            TaskState::Blocked => "blocked",
+            TaskState::Zombie => "zombie",
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -26,9 +149,33 @@ pub struct Stack {
 
 pub struct TaskStruct {
     pub id: Option<u64>,
+    pub parent: Option<u64>,
+    // First spawn argument, NUL padded.
+    pub name: [u8; TASK_NAME_LEN],
     pub state: TaskState,
+    pub exit_code: i32,
+    pub exit_waiters: WaitQueue,
+    // Hart the task last ran on, and whether it is still running there.
+    pub hart: usize,
+    pub on_cpu: bool,
+    // Bit n set = may run on hart n.
+    pub affinity: u64,
+    pub priority: usize,
+    // Ticks per turn, and how many of them are left in the current one.
+    pub time_slice: u64,
+    pub slice_left: u64,
+    // Deadline of a normal task under the `sched-edf` policy, in ticks.
+    pub deadline: u64,
+    // Set for real-time tasks, which run ahead of all others.
+    pub rt: Option<RtParams>,
+    // Timer ticks spent running.
+    pub cpu_ticks: u64,
+    pub trace: TraceMode,
     pub stack_ptr: Option<Arc<Stack>>,
-    pub sleep_until: Option<u64>,
+    pub address_space: Option<AddressSpace>,
+    pub satp: u64,
+    pub kernel_satp: u64,
+    pub kernel_sp: u64,
     pub xepc: u64,
     pub xcause: u64,
     pub ra: u64,
@@ -40,6 +187,9 @@ pub struct TaskStruct {
     pub a: [u64; 8],
 }
 
+pub const OFFSET_SATP: usize = offset_of!(TaskStruct, satp);
+pub const OFFSET_KERNEL_SATP: usize = offset_of!(TaskStruct, kernel_satp);
+pub const OFFSET_KERNEL_SP: usize = offset_of!(TaskStruct, kernel_sp);
 pub const OFFSET_XEPC: usize = offset_of!(TaskStruct, xepc);
 pub const OFFSET_XCAUSE: usize = offset_of!(TaskStruct, xcause);
 
@@ -82,21 +232,20 @@ pub const OFFSET_A6: usize = OFFSET_A + 6 * 8;
 pub const OFFSET_A7: usize = OFFSET_A + 7 * 8;
 
 impl Stack {
+    // Stacks are whole pages taken straight from the frame allocator.
     pub fn new(nbytes: usize) -> Option<Self> {
-        unsafe {
-            Some(Self {
-                stack: NonNull::new(malloc(nbytes)?)?,
-                size: nbytes,
-            })
-        }
+        let pages = nbytes.div_ceil(PAGE_SIZE);
+        let addr = frame::alloc_contiguous(pages)?;
+        Some(Self {
+            stack: NonNull::new(addr as *mut u8)?,
+            size: pages * PAGE_SIZE,
+        })
     }
 }
 
 impl Drop for Stack {
     fn drop(&mut self) {
-        unsafe {
-            free(self.stack.as_ptr());
-        }
+        frame::free_contiguous(self.stack.as_ptr() as usize, self.size / PAGE_SIZE);
     }
 }
 
@@ -104,9 +253,26 @@ impl TaskStruct {
     pub const fn new() -> Self {
         Self {
             id: None,
+            parent: None,
+            name: [0; TASK_NAME_LEN],
             state: TaskState::None,
+            exit_code: 0,
+            exit_waiters: WaitQueue::new(),
+            hart: 0,
+            on_cpu: false,
+            affinity: ALL_HARTS,
+            priority: DEFAULT_PRIORITY,
+            time_slice: default_time_slice(DEFAULT_PRIORITY),
+            slice_left: 0,
+            deadline: 0,
+            rt: None,
+            cpu_ticks: 0,
+            trace: TraceMode::Off,
             stack_ptr: None,
-            sleep_until: None,
+            address_space: None,
+            satp: 0,
+            kernel_satp: 0,
+            kernel_sp: 0,
             xepc: 0,
             xcause: 0,
             ra: 0,
@@ -118,4 +284,23 @@ impl TaskStruct {
             a: [0; 8],
         }
     }
+
+    /// Bytes of its stack the task used as of its last trap.
+    pub fn stack_used(&self) -> usize {
+        match self.stack_ptr.as_ref() {
+            Some(s) => {
+                let s = s.get_ref();
+                let top = s.stack.as_ptr() as usize + s.size;
+                top.saturating_sub(self.sp as usize).min(s.size)
+            }
+            None => 0,
+        }
+    }
+
+    pub fn stack_size(&self) -> usize {
+        match self.stack_ptr.as_ref() {
+            Some(s) => s.get_ref().size,
+            None => 0,
+        }
+    }
 }
@@ -0,0 +1,97 @@
+use super::realtime::{Utilization, charge_budget, check_queued};
+use super::{SchedPolicy, read};
+use crate::task::scheduler::TaskNode;
+use crate::task::{RtParams, TaskStruct};
+use crate::timer::get_current_tick;
+use crate::utils::list::LinkedList;
+
+/// Earliest deadline first for every task, not only the real-time ones. A
+/// real-time task has the deadline of its current job; a normal task gets
+/// one a time slice after it became ready, so the short slices of urgent
+/// priorities come first without starving the long ones.
+///
+/// Real-time tasks are admitted as under `RealTime`, but normal tasks
+/// compete with them by deadline, so their deadlines only hold while the
+/// normal load leaves room.
+pub struct Edf {
+    queue: LinkedList<TaskStruct>,
+    utilization: Utilization,
+}
+
+fn deadline(task: &TaskStruct) -> u64 {
+    match task.rt {
+        Some(rt) => rt.abs_deadline(),
+        None => task.deadline,
+    }
+}
+
+impl Edf {
+    fn earliest_deadline(&self) -> Option<(u64, TaskNode)> {
+        self.queue
+            .iter()
+            .into_iter()
+            .flatten()
+            .map(|task| (read(&task, deadline), task))
+            .min_by_key(|(deadline, _)| *deadline)
+    }
+}
+
+impl SchedPolicy for Edf {
+    fn new() -> Self {
+        Self {
+            queue: LinkedList::new(),
+            utilization: Utilization::default(),
+        }
+    }
+
+    fn enqueue(&self, task: TaskNode) {
+        let now = get_current_tick();
+        // A task preempted before its deadline keeps it.
+        if let Some(t) = task.get_ref().lock().value.as_mut()
+            && t.rt.is_none()
+            && t.deadline <= now
+        {
+            t.deadline = now.saturating_add(t.time_slice);
+        }
+        self.queue.push_back_node(task);
+    }
+
+    fn dequeue(&self, task: TaskNode) -> Option<TaskNode> {
+        self.queue.remove_node(task)
+    }
+
+    fn pick_next(&self) -> Option<TaskNode> {
+        let (_, task) = self.earliest_deadline()?;
+        LinkedList::remove_node_safe(task)
+    }
+
+    fn tick(&self, task: &mut TaskStruct) -> bool {
+        let now = get_current_tick();
+        check_queued(&self.queue, now);
+        if task.rt.is_some() {
+            if charge_budget(task, now) {
+                return true;
+            }
+        } else {
+            task.slice_left = task.slice_left.saturating_sub(1);
+            if task.slice_left == 0 {
+                return true;
+            }
+        }
+        let own = deadline(task);
+        self.earliest_deadline()
+            .is_some_and(|(queued, _)| queued < own)
+    }
+
+    fn admit(&self, rt: &RtParams) -> bool {
+        self.utilization.admit(rt)
+    }
+
+    fn retire(&self, rt: &RtParams) {
+        self.utilization.retire(rt)
+    }
+
+    fn tasks(&self) -> impl Iterator<Item = TaskNode> + '_ {
+        self.queue.iter().into_iter().flatten()
+    }
+}
@@ -0,0 +1,95 @@
+//! Scheduling policies. The scheduler core keeps blocked, zombie and free
+//! tasks and moves tasks between harts; the policy only decides in which
+//! order the runnable tasks of one hart get it, and for how long.
+//!
+//! Exactly one policy is built in, chosen with a Cargo feature:
+//! `sched-priority` (the default) or `sched-rr` for normal tasks, with
+//! real-time tasks run ahead of them under `realtime::RealTime`, or
+//! `sched-edf`, which orders every task by deadline.
+
+pub mod edf;
+pub mod priority;
+pub mod realtime;
+pub mod round_robin;
+
+use crate::task::scheduler::TaskNode;
+use crate::task::{RtParams, TaskStruct};
+
+#[cfg(any(
+    all(feature = "sched-rr", feature = "sched-priority"),
+    all(feature = "sched-rr", feature = "sched-edf"),
+    all(feature = "sched-priority", feature = "sched-edf"),
+))]
+compile_error!("Pick one scheduling policy, e.g. --no-default-features --features sched-rr");
+#[cfg(not(any(
+    feature = "sched-rr",
+    feature = "sched-priority",
+    feature = "sched-edf"
+)))]
+compile_error!(
+    "No scheduling policy enabled, use the sched-priority, sched-rr or sched-edf feature"
+);
+
+#[cfg(feature = "sched-rr")]
+pub type Policy = realtime::RealTime<round_robin::RoundRobin>;
+#[cfg(feature = "sched-priority")]
+pub type Policy = realtime::RealTime<priority::Priority>;
+#[cfg(feature = "sched-edf")]
+pub type Policy = edf::Edf;
+
+/// Run queue of one hart. It only ever holds tasks that are ready and not
+/// running; the task a hart runs is taken out by `pick_next` and handed back
+/// to `enqueue` once it gives up the hart while still ready.
+///
+/// Every method is called with the scheduler lock held.
+pub trait SchedPolicy {
+    fn new() -> Self;
+
+    /// Queue a ready task.
+    fn enqueue(&self, task: TaskNode);
+
+    /// Take `task` out of the queue, or `None` if it is not queued here.
+    fn dequeue(&self, task: TaskNode) -> Option<TaskNode>;
+
+    /// Take out the task that should run next.
+    fn pick_next(&self) -> Option<TaskNode>;
+
+    /// Account a timer tick to the running `task` and say whether it should
+    /// give up the hart.
+    fn tick(&self, task: &mut TaskStruct) -> bool;
+
+    /// `task` stopped running to sleep or wait for an event.
+    fn on_block(&self, _task: &mut TaskStruct) {}
+
+    /// `task` is ready again and about to be queued.
+    fn on_wake(&self, _task: &mut TaskStruct) {}
+
+    /// Reserve room on this hart for a real-time task with `rt`. Policies
+    /// without real-time support admit none.
+    fn admit(&self, _rt: &RtParams) -> bool {
+        false
+    }
+
+    /// Give back what `admit` reserved for `rt`.
+    fn retire(&self, _rt: &RtParams) {}
+
+    /// Every queued task, for lookups and stealing.
+    fn tasks(&self) -> impl Iterator<Item = TaskNode> + '_;
+
+    fn len(&self) -> usize {
+        self.tasks().count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Read a field of a queued task without keeping its lock.
+fn read<R: Default>(task: &TaskNode, f: impl FnOnce(&TaskStruct) -> R) -> R {
+    task.get_ref()
+        .lock()
+        .value
+        .as_ref()
+        .map_or_else(R::default, f)
+}
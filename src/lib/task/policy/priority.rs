@@ -0,0 +1,52 @@
+use super::{SchedPolicy, read};
+use crate::task::scheduler::TaskNode;
+use crate::task::{PRIORITY_LEVELS, TaskStruct};
+use crate::utils::list::LinkedList;
+
+/// Round robin within each priority level, and a level only runs while
+/// every level above it is empty. A task that becomes ready preempts a
+/// lower priority one at the next tick.
+pub struct Priority {
+    // Indexed by priority, 0 first.
+    queues: [LinkedList<TaskStruct>; PRIORITY_LEVELS],
+}
+
+impl Priority {
+    fn ready_above(&self, priority: usize) -> bool {
+        self.queues[..priority.min(PRIORITY_LEVELS)]
+            .iter()
+            .any(|q| !q.is_empty())
+    }
+}
+
+impl SchedPolicy for Priority {
+    fn new() -> Self {
+        Self {
+            queues: core::array::from_fn(|_| LinkedList::new()),
+        }
+    }
+
+    fn enqueue(&self, task: TaskNode) {
+        let priority = read(&task, |t| t.priority).min(PRIORITY_LEVELS - 1);
+        self.queues[priority].push_back_node(task);
+    }
+
+    fn dequeue(&self, task: TaskNode) -> Option<TaskNode> {
+        self.queues.iter().find_map(|q| q.remove_node(task.clone()))
+    }
+
+    fn pick_next(&self) -> Option<TaskNode> {
+        self.queues.iter().find_map(|q| q.pop_front())
+    }
+
+    fn tick(&self, task: &mut TaskStruct) -> bool {
+        task.slice_left = task.slice_left.saturating_sub(1);
+        task.slice_left == 0 || self.ready_above(task.priority)
+    }
+
+    fn tasks(&self) -> impl Iterator<Item = TaskNode> + '_ {
+        self.queues
+            .iter()
+            .flat_map(|q| q.iter().into_iter().flatten())
+    }
+}
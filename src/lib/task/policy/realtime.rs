@@ -0,0 +1,224 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{SchedPolicy, read};
+use crate::task::scheduler::TaskNode;
+use crate::task::{RtParams, TaskStruct};
+use crate::timer::get_current_tick;
+use crate::uart::{print_integer, print_string};
+use crate::utils::list::LinkedList;
+
+// Utilization of a whole hart.
+const FULL_UTILIZATION: u64 = 1000;
+
+static DEADLINE_MISSES: AtomicU64 = AtomicU64::new(0);
+static BUDGET_OVERRUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Deadlines missed by all real-time tasks since boot.
+pub fn deadline_misses() -> u64 {
+    DEADLINE_MISSES.load(Ordering::Relaxed)
+}
+
+/// Jobs of all real-time tasks cut off for using up their budget since
+/// boot.
+pub fn budget_overruns() -> u64 {
+    BUDGET_OVERRUNS.load(Ordering::Relaxed)
+}
+
+/// Real-time tasks, dispatched by earliest deadline first, ahead of the
+/// tasks of the normal policy `P`.
+///
+/// Each real-time task is pinned to the hart that admitted it. A hart only
+/// admits tasks while the sum of their budget/deadline stays at most 1,
+/// which keeps every deadline under EDF as long as the tasks stay within
+/// their budgets. A task that uses up its budget waits for its next period.
+pub struct RealTime<P> {
+    rt_queue: LinkedList<TaskStruct>,
+    normal: P,
+    utilization: Utilization,
+}
+
+/// Real-time tasks admitted to one hart, in 1/FULL_UTILIZATION of it.
+#[derive(Default)]
+pub struct Utilization(AtomicU64);
+
+impl Utilization {
+    /// Reserve room for `rt` if the hart has it left.
+    pub fn admit(&self, rt: &RtParams) -> bool {
+        let needed = utilization(rt);
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (used + needed <= FULL_UTILIZATION).then_some(used + needed)
+            })
+            .is_ok()
+    }
+
+    pub fn retire(&self, rt: &RtParams) {
+        self.0.fetch_sub(utilization(rt), Ordering::Relaxed);
+    }
+}
+
+fn utilization(rt: &RtParams) -> u64 {
+    (rt.budget * FULL_UTILIZATION).div_ceil(rt.deadline)
+}
+
+fn report_miss(id: Option<u64>, rt: &mut RtParams, now: u64) {
+    rt.missed = true;
+    rt.misses += 1;
+    DEADLINE_MISSES.fetch_add(1, Ordering::Relaxed);
+    print_string("Task ");
+    print_integer(id.unwrap_or(0));
+    print_string(" missed its deadline at tick ");
+    print_integer(now);
+    print_string("\n");
+}
+
+fn report_overrun(id: Option<u64>, rt: &mut RtParams, now: u64) {
+    rt.overruns += 1;
+    BUDGET_OVERRUNS.fetch_add(1, Ordering::Relaxed);
+    print_string("Task ");
+    print_integer(id.unwrap_or(0));
+    print_string(" ran out of budget at tick ");
+    print_integer(now);
+    print_string("\n");
+}
+
+// Report a job still unfinished past its deadline, once.
+fn check_deadline(id: Option<u64>, rt: &mut RtParams, now: u64) {
+    if !rt.missed && now > rt.abs_deadline() {
+        report_miss(id, rt, now);
+    }
+}
+
+/// Report the real-time jobs waiting in `queue` that are past their
+/// deadline.
+pub fn check_queued(queue: &LinkedList<TaskStruct>, now: u64) {
+    if let Some(iter) = queue.iter() {
+        for queued in iter {
+            let mut guard = queued.get_ref().lock();
+            if let Some(t) = guard.value.as_mut() {
+                let id = t.id;
+                if let Some(rt) = t.rt.as_mut() {
+                    check_deadline(id, rt, now);
+                }
+            }
+        }
+    }
+}
+
+/// Charge a tick to the job of the running real-time `task`. Returns true
+/// if that used up its budget, in which case its next job is started and
+/// the scheduler puts it to sleep until that job is released.
+pub fn charge_budget(task: &mut TaskStruct, now: u64) -> bool {
+    let id = task.id;
+    let rt = match task.rt.as_mut() {
+        Some(rt) => rt,
+        None => return false,
+    };
+    check_deadline(id, rt, now);
+    rt.budget_left = rt.budget_left.saturating_sub(1);
+    if rt.budget_left > 0 {
+        return false;
+    }
+    // Out of budget. That is the task's own overrun, not a missed deadline:
+    // it is cut off whether or not its deadline has passed.
+    report_overrun(id, rt, now);
+    let release = rt.next_release(now);
+    rt.start_job(release);
+    true
+}
+
+impl<P: SchedPolicy> RealTime<P> {
+    fn earliest_deadline(&self) -> Option<(u64, TaskNode)> {
+        self.rt_queue
+            .iter()
+            .into_iter()
+            .flatten()
+            .map(|task| {
+                (
+                    read(&task, |t| t.rt.map_or(u64::MAX, |rt| rt.abs_deadline())),
+                    task,
+                )
+            })
+            .min_by_key(|(deadline, _)| *deadline)
+    }
+
+    // Account a tick to the running real-time task. Returns whether it has
+    // to give up the hart.
+    fn tick_rt(&self, task: &mut TaskStruct, now: u64) -> bool {
+        if charge_budget(task, now) {
+            return true;
+        }
+        let deadline = task.rt.map_or(u64::MAX, |rt| rt.abs_deadline());
+        self.earliest_deadline()
+            .is_some_and(|(queued, _)| queued < deadline)
+    }
+}
+
+impl<P: SchedPolicy> SchedPolicy for RealTime<P> {
+    fn new() -> Self {
+        Self {
+            rt_queue: LinkedList::new(),
+            normal: P::new(),
+            utilization: Utilization::default(),
+        }
+    }
+
+    fn enqueue(&self, task: TaskNode) {
+        if read(&task, |t| t.rt.is_some()) {
+            self.rt_queue.push_back_node(task);
+        } else {
+            self.normal.enqueue(task);
+        }
+    }
+
+    fn dequeue(&self, task: TaskNode) -> Option<TaskNode> {
+        self.rt_queue
+            .remove_node(task.clone())
+            .or_else(|| self.normal.dequeue(task))
+    }
+
+    fn pick_next(&self) -> Option<TaskNode> {
+        match self.earliest_deadline() {
+            Some((_, task)) => LinkedList::remove_node_safe(task),
+            None => self.normal.pick_next(),
+        }
+    }
+
+    fn tick(&self, task: &mut TaskStruct) -> bool {
+        let now = get_current_tick();
+        check_queued(&self.rt_queue, now);
+        if task.rt.is_some() {
+            self.tick_rt(task, now)
+        } else {
+            self.normal.tick(task) || !self.rt_queue.is_empty()
+        }
+    }
+
+    fn on_block(&self, task: &mut TaskStruct) {
+        if task.rt.is_none() {
+            self.normal.on_block(task);
+        }
+    }
+
+    fn on_wake(&self, task: &mut TaskStruct) {
+        if task.rt.is_none() {
+            self.normal.on_wake(task);
+        }
+    }
+
+    fn admit(&self, rt: &RtParams) -> bool {
+        self.utilization.admit(rt)
+    }
+
+    fn retire(&self, rt: &RtParams) {
+        self.utilization.retire(rt)
+    }
+
+    fn tasks(&self) -> impl Iterator<Item = TaskNode> + '_ {
+        self.rt_queue
+            .iter()
+            .into_iter()
+            .flatten()
+            .chain(self.normal.tasks())
+    }
+}
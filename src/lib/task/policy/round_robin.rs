@@ -0,0 +1,39 @@
+use super::SchedPolicy;
+use crate::task::TaskStruct;
+use crate::task::scheduler::TaskNode;
+use crate::utils::list::LinkedList;
+
+/// Every task takes turns of its own time slice, in the order it became
+/// ready. Priorities are ignored.
+pub struct RoundRobin {
+    queue: LinkedList<TaskStruct>,
+}
+
+impl SchedPolicy for RoundRobin {
+    fn new() -> Self {
+        Self {
+            queue: LinkedList::new(),
+        }
+    }
+
+    fn enqueue(&self, task: TaskNode) {
+        self.queue.push_back_node(task);
+    }
+
+    fn dequeue(&self, task: TaskNode) -> Option<TaskNode> {
+        self.queue.remove_node(task)
+    }
+
+    fn pick_next(&self) -> Option<TaskNode> {
+        self.queue.pop_front()
+    }
+
+    fn tick(&self, task: &mut TaskStruct) -> bool {
+        task.slice_left = task.slice_left.saturating_sub(1);
+        task.slice_left == 0
+    }
+
+    fn tasks(&self) -> impl Iterator<Item = TaskNode> + '_ {
+        self.queue.iter().into_iter().flatten()
+    }
+}
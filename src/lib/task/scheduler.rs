@@ -1,19 +1,37 @@
 use core::cell::UnsafeCell;
 
 use crate::csr;
+use crate::mm;
+use crate::mm::address_space::AddressSpace;
+use crate::mutex::Lock;
+use crate::mutex::Mutex;
+use crate::mutex::SpinLock;
+use crate::mutex::YieldLock;
 use crate::riscv::PrivilegeMode;
+use crate::smp::MAX_HARTS;
 use crate::syscall::sys_exit;
+use crate::syscall::trace::TraceMode;
+use crate::task::RtParams;
 use crate::task::Stack;
 use crate::task::TaskState;
 use crate::task::TaskStruct;
-use crate::task::{USER_STACK_ALIGNMENT, USER_STACK_SIZE};
+use crate::task::policy::{Policy, SchedPolicy};
+use crate::task::{
+    ALL_HARTS, KERNEL_STACK_SIZE, PRIORITY_LEVELS, TASK_NAME_LEN, USER_STACK_ALIGNMENT,
+    USER_STACK_SIZE, default_time_slice,
+};
+use crate::timer;
 use crate::timer::get_current_tick;
-use crate::uart::print_string;
-use crate::utils::list::LinkedList;
+use crate::timer::timer_queue::{add_timer, next_expiry};
+use crate::uart::{print_integer, print_string};
+use crate::utils::list::{LinkedList, ListNode};
 use crate::utils::rc::Arc;
 
-pub type RawTaskFn = fn(argc: u64, argv: &[&str]);
+pub type RawTaskFn = fn(argc: u64, argv: &[&str]) -> i32;
+pub type TaskNode = Arc<Mutex<ListNode<TaskStruct>, YieldLock>>;
+pub const MAX_ARGS_LEN: usize = 256;
 pub static SCHEDULER: SafeStaticScheduler = SafeStaticScheduler {
+    lock: SpinLock::new(),
     inner: UnsafeCell::new(Scheduler::new()),
 };
 
@@ -30,31 +48,74 @@ fn align_stack_ptr(s: &Stack) -> usize {
     stack_bottom as usize + padding
 }
 
+// Copy the spawn arguments to the top of the new task's stack, because the
+// caller's buffer is not mapped in the new address space. Returns the address
+// and length of the copy and the initial stack pointer. `len` is at most
+// `MAX_ARGS_LEN`.
+fn copy_args_to_stack(s: &Stack, args: *const u8, len: usize) -> (usize, usize, usize) {
+    let len = if args.is_null() { 0 } else { len };
+    let args_addr = (align_stack_ptr(s) - len) & !(USER_STACK_ALIGNMENT - 1);
+    unsafe {
+        core::ptr::copy_nonoverlapping(args, args_addr as *mut u8, len);
+    }
+    (args_addr, len, args_addr)
+}
+
+/// Tasks that can run sit in the run queue of one hart, ordered by the
+/// scheduling policy. Blocked, zombie and free tasks are shared by all harts.
+///
+/// Everything here is guarded by the one lock taken through
+/// `SCHEDULER.with`; no list is touched without it.
 pub struct Scheduler {
-    pub running_list: Option<LinkedList<TaskStruct>>,
-    pub waiting_list: Option<LinkedList<TaskStruct>>,
     pub blocked_list: Option<LinkedList<TaskStruct>>,
+    pub zombie_list: Option<LinkedList<TaskStruct>>,
     pub pool: Option<LinkedList<TaskStruct>>,
-    pub kernel_task: Option<TaskStruct>,
-    pub idle_task: Option<TaskStruct>,
+    pub harts: [Option<Hart>; MAX_HARTS],
     pub new_task_id: u64,
 }
 
+/// State every hart keeps for itself.
+pub struct Hart {
+    // Save area for M-mode traps, pointed to by mscratch.
+    pub kernel_task: TaskStruct,
+    pub idle_task: TaskStruct,
+    pub trap_stack: Stack,
+    pub machine_trap_stack: Stack,
+    // Task running here, out of the run queue until it gives up the hart.
+    pub current: Option<TaskNode>,
+    pub run_queue: Policy,
+}
+
+impl Hart {
+    fn load(&self) -> usize {
+        self.run_queue.len() + self.current.is_some() as usize
+    }
+}
+
 pub struct SafeStaticScheduler {
+    lock: SpinLock,
     pub inner: UnsafeCell<Scheduler>,
 }
 
 unsafe impl Sync for SafeStaticScheduler {}
 
+impl SafeStaticScheduler {
+    /// Run `f` with the scheduler locked against the other harts.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Scheduler) -> R) -> R {
+        self.lock.lock();
+        let result = f(unsafe { &mut *self.inner.get() });
+        self.lock.unlock();
+        result
+    }
+}
+
 impl Scheduler {
     pub const fn new() -> Self {
         Self {
-            running_list: None,
-            waiting_list: None,
             blocked_list: None,
+            zombie_list: None,
             pool: None,
-            kernel_task: None,
-            idle_task: None,
+            harts: [const { None }; MAX_HARTS],
             new_task_id: 1,
         }
     }
@@ -67,15 +128,27 @@ fn idle_task() {
 }
 
 pub fn init() {
-    let scheduler = unsafe { &mut *SCHEDULER.inner.get() };
-    scheduler.running_list = Some(LinkedList::new());
-    scheduler.waiting_list = Some(LinkedList::new());
-    scheduler.blocked_list = Some(LinkedList::new());
-    scheduler.pool = Some(LinkedList::new());
-    // create kernel task struct
-    csr::write_mscratch(
-        scheduler.kernel_task.get_or_insert(TaskStruct::new()) as *const TaskStruct as u64,
-    );
+    SCHEDULER.with(|scheduler| {
+        scheduler.blocked_list = Some(LinkedList::new());
+        scheduler.zombie_list = Some(LinkedList::new());
+        scheduler.pool = Some(LinkedList::new());
+        scheduler.new_task_id = 1;
+    });
+    print_string("Scheduler init success\n");
+}
+
+// Runs on every hart before it first enters the scheduler, in M-mode unless
+// an SBI firmware booted us.
+pub fn init_hart(hart_id: usize) {
+    // Traps run on kernel stacks, never on whatever sp the task left behind.
+    let trap_stack = match Stack::new(KERNEL_STACK_SIZE) {
+        Some(s) => s,
+        None => panic!(),
+    };
+    let machine_trap_stack = match Stack::new(KERNEL_STACK_SIZE) {
+        Some(s) => s,
+        None => panic!(),
+    };
     // create idle task stack
     let idle_task_stack = match crate::task::Stack::new(USER_STACK_SIZE) {
         Some(s) => match crate::utils::rc::Arc::new(s) {
@@ -84,27 +157,55 @@ pub fn init() {
         },
         _ => panic!(),
     };
-    let idle_task_struct = scheduler.idle_task.get_or_insert(TaskStruct::new());
-    idle_task_struct.sp = align_stack_ptr(idle_task_stack.get_ref()) as u64;
-    idle_task_struct.stack_ptr = Some(idle_task_stack);
-    idle_task_struct.xepc = idle_task as u64;
-    csr::write_sepc(idle_task_struct.xepc);
-    csr::write_sscratch(idle_task_struct as *const TaskStruct as u64);
-    idle_task_struct.xepc = idle_task as u64;
-    scheduler.new_task_id = 1;
-    print_string("Scheduler init success\n");
+    SCHEDULER.with(|scheduler| {
+        let hart = scheduler.harts[hart_id].insert(Hart {
+            kernel_task: TaskStruct::new(),
+            idle_task: TaskStruct::new(),
+            trap_stack,
+            machine_trap_stack,
+            current: None,
+            run_queue: Policy::new(),
+        });
+        let trap_sp = align_stack_ptr(&hart.trap_stack) as u64;
+        // create kernel task struct
+        let kernel_task_struct = &mut hart.kernel_task;
+        kernel_task_struct.hart = hart_id;
+        kernel_task_struct.kernel_sp = align_stack_ptr(&hart.machine_trap_stack) as u64;
+        #[cfg(not(feature = "sbi"))]
+        csr::write_mscratch(kernel_task_struct as *const TaskStruct as u64);
+        let idle_task_struct = &mut hart.idle_task;
+        idle_task_struct.hart = hart_id;
+        idle_task_struct.sp = align_stack_ptr(idle_task_stack.get_ref()) as u64;
+        idle_task_struct.stack_ptr = Some(idle_task_stack);
+        idle_task_struct.xepc = idle_task as u64;
+        idle_task_struct.satp = mm::kernel_satp();
+        idle_task_struct.kernel_satp = mm::kernel_satp();
+        idle_task_struct.kernel_sp = trap_sp;
+        csr::write_sepc(idle_task_struct.xepc);
+        csr::write_sscratch(idle_task_struct as *const TaskStruct as u64);
+    });
+    print_string("Hart ");
+    print_integer(hart_id as u64);
+    print_string(" init success\n");
+}
+
+/// Hart this code runs on. Only valid in S-mode, where sscratch holds the
+/// task being handled, which records the hart it was scheduled on.
+pub fn current_hart() -> usize {
+    let task = csr::read_sscratch() as *const TaskStruct;
+    unsafe { (*task).hart }
 }
 
 pub fn task_start(task: RawTaskFn, args: *const u8, len: usize) {
     use core::slice;
     use core::str;
     if args.is_null() && len > 0 {
-        return sys_exit(0);
+        return sys_exit(-1);
     }
     let args = unsafe { slice::from_raw_parts(args, len) };
     let s = match str::from_utf8(args) {
         Ok(s) => s.trim_end_matches('\0'),
-        Err(_) => return sys_exit(0),
+        Err(_) => return sys_exit(-1),
     };
     let mut argv_buf: [&str; 5] = [""; 5];
     let mut argc = 0;
@@ -118,20 +219,65 @@ pub fn task_start(task: RawTaskFn, args: *const u8, len: usize) {
         argv_buf[argc] = token;
         argc += 1;
     }
-    task(argc as u64, &argv_buf[..argc]);
-    sys_exit(0);
+    let code = task(argc as u64, &argv_buf[..argc]);
+    sys_exit(code);
 }
 
-pub fn task_create(task: *const u8, args: *const u8, len: usize) -> Option<u64> {
-    let scheduler = unsafe { &mut *SCHEDULER.inner.get() };
+/// Create a task running `task` at `priority`. Only a task with a `parent`
+/// is kept as a zombie after it exits, the kernel never waits for the ones
+/// it starts. The new task traces its syscalls like its parent.
+pub fn task_create(
+    task: *const u8,
+    args: *const u8,
+    len: usize,
+    parent: Option<&TaskStruct>,
+    priority: usize,
+) -> Option<u64> {
+    if priority >= PRIORITY_LEVELS {
+        return None;
+    }
+    SCHEDULER.with(|scheduler| create_task(scheduler, task, args, len, parent, priority, None))
+}
+
+/// Create a real-time task with the timing in `rt`, pinned to the first
+/// hart that still has room for it. Returns `None` if no hart admits it.
+pub fn task_create_rt(
+    task: *const u8,
+    args: *const u8,
+    len: usize,
+    parent: Option<&TaskStruct>,
+    rt: RtParams,
+) -> Option<u64> {
+    SCHEDULER.with(|scheduler| {
+        let hart_id = scheduler
+            .harts
+            .iter()
+            .position(|h| h.as_ref().is_some_and(|h| h.run_queue.admit(&rt)))?;
+        let id = create_task(scheduler, task, args, len, parent, 0, Some((hart_id, rt)));
+        if id.is_none() {
+            hart(scheduler, hart_id).run_queue.retire(&rt);
+        }
+        id
+    })
+}
+
+fn create_task(
+    scheduler: &mut Scheduler,
+    task: *const u8,
+    args: *const u8,
+    len: usize,
+    parent: Option<&TaskStruct>,
+    priority: usize,
+    rt: Option<(usize, RtParams)>,
+) -> Option<u64> {
+    // Rather than run the task with its arguments cut off.
+    if len > MAX_ARGS_LEN {
+        return None;
+    }
     let pool = match scheduler.pool.as_mut() {
         Some(list) => list,
         None => return None,
     };
-    let running = match scheduler.running_list.as_mut() {
-        Some(list) => list,
-        None => return None,
-    };
     let new_task = if pool.is_empty() {
         let new_task_stack = match Stack::new(USER_STACK_SIZE) {
             Some(s) => Arc::new(s),
@@ -159,166 +305,553 @@ pub fn task_create(task: *const u8, args: *const u8, len: usize) -> Option<u64>
             Some(t) => t,
             None => return None,
         };
+        let stack = match new_task_struct.stack_ptr.as_ref() {
+            Some(s) => s.get_ref(),
+            None => return None,
+        };
+        let mut space = AddressSpace::new_user()?;
+        space.map_user(stack.stack.as_ptr() as usize, stack.size)?;
+        space.map_kernel(
+            new_task_struct as *const TaskStruct as usize,
+            core::mem::size_of::<TaskStruct>(),
+        )?;
+        let (args_addr, args_len, sp) = copy_args_to_stack(stack, args, len);
+
         new_task_struct.state = TaskState::Ready;
+        new_task_struct.exit_code = 0;
+        new_task_struct.parent = parent.and_then(|p| p.id);
+        new_task_struct.name = task_name(args, len);
+        new_task_struct.cpu_ticks = 0;
+        new_task_struct.trace = parent.map_or(TraceMode::Off, |p| p.trace);
+        new_task_struct.affinity = match rt {
+            Some((hart_id, _)) => 1 << hart_id,
+            None => ALL_HARTS,
+        };
+        new_task_struct.rt = rt.map(|(_, mut rt)| {
+            rt.start_job(get_current_tick());
+            rt
+        });
+        new_task_struct.priority = priority;
+        new_task_struct.time_slice = default_time_slice(priority);
         new_task_struct.id = Some(scheduler.new_task_id);
         scheduler.new_task_id += 1;
-
-        let stack_ptr = match new_task_struct.stack_ptr.as_ref() {
-            Some(s) => s,
-            None => return None,
-        };
-        let align_sp = align_stack_ptr(stack_ptr.get_ref());
-        new_task_struct.sp = align_sp as u64;
+        new_task_struct.sp = sp as u64;
+        new_task_struct.satp = space.satp();
+        new_task_struct.kernel_satp = mm::kernel_satp();
+        new_task_struct.address_space = Some(space);
         new_task_struct.xepc = task_start as u64;
         new_task_struct.a[0] = task as u64;
-        new_task_struct.a[1] = args as u64;
-        new_task_struct.a[2] = len as u64;
+        new_task_struct.a[1] = args_addr as u64;
+        new_task_struct.a[2] = args_len as u64;
         new_task_struct.id
     };
-    running.push_back_node(new_task);
+    enqueue(scheduler, new_task);
     id
 }
 
-pub fn schedule() {
-    let scheduler = unsafe { &mut *SCHEDULER.inner.get() };
-    let running_is_empty = {
-        let running = match scheduler.running_list.as_mut() {
-            Some(p) => p,
-            None => panic!("Scheduler list should not be None\n"),
-        };
-        running.is_empty()
-    };
-    if running_is_empty {
-        let mut tmp = scheduler.running_list.take();
-        scheduler.running_list = scheduler.waiting_list.take();
-        scheduler.waiting_list = tmp.take();
+// The first word of the spawn arguments, which are in kernel memory.
+fn task_name(args: *const u8, len: usize) -> [u8; TASK_NAME_LEN] {
+    let mut name = [0; TASK_NAME_LEN];
+    if args.is_null() {
+        return name;
     }
-    let blocked = match scheduler.blocked_list.as_mut() {
-        Some(p) => p,
-        None => panic!("Scheduler list should not be None\n"),
-    };
-    let running = match scheduler.running_list.as_mut() {
-        Some(p) => p,
+    let args = unsafe { core::slice::from_raw_parts(args, len.min(MAX_ARGS_LEN)) };
+    let word = args
+        .split(|&c| c == b' ' || c == b'\0')
+        .find(|w| !w.is_empty())
+        .unwrap_or(&[]);
+    let n = word.len().min(TASK_NAME_LEN);
+    name[..n].copy_from_slice(&word[..n]);
+    name
+}
+
+fn list(list: &Option<LinkedList<TaskStruct>>) -> &LinkedList<TaskStruct> {
+    match list.as_ref() {
+        Some(l) => l,
         None => panic!("Scheduler list should not be None\n"),
+    }
+}
+
+fn hart(scheduler: &Scheduler, hart_id: usize) -> &Hart {
+    match &scheduler.harts[hart_id] {
+        Some(h) => h,
+        None => panic!("Hart is not initialized\n"),
+    }
+}
+
+// Put a ready task on the least loaded hart its affinity allows, or on any
+// hart if none of those is up. An idle hart gets kicked to pick it up.
+fn enqueue(scheduler: &Scheduler, task: TaskNode) {
+    let affinity = match task.get_ref().lock().value.as_ref() {
+        Some(t) => t.affinity,
+        None => ALL_HARTS,
     };
-    let waiting = match scheduler.waiting_list.as_mut() {
-        Some(p) => p,
-        None => panic!("Scheduler list should not be None\n"),
+    let target = scheduler
+        .harts
+        .iter()
+        .enumerate()
+        .filter_map(|(id, hart)| hart.as_ref().map(|h| (id, h)))
+        .min_by_key(|(id, hart)| (affinity & (1 << id) == 0, hart.load()));
+    match target {
+        Some((id, hart)) => {
+            hart.run_queue.enqueue(task);
+            if hart.current.is_none() && id != current_hart() {
+                timer::kick(id as u64);
+            }
+        }
+        None => panic!("No hart to run the task on\n"),
+    }
+}
+
+// Mark `task` as running on `hart_id` and return its pc and struct address.
+fn claim(task: &mut TaskStruct, hart_id: usize, trap_sp: u64) -> (u64, u64) {
+    task.on_cpu = true;
+    task.hart = hart_id;
+    task.kernel_sp = trap_sp;
+    task.slice_left = task.time_slice;
+    (task.xepc, task as *const TaskStruct as u64)
+}
+
+pub fn schedule() {
+    let hart_id = current_hart();
+    SCHEDULER.with(|scheduler| pick_next(scheduler, hart_id));
+}
+
+/// Account a timer tick to `task`, the one running on this hart, and say
+/// whether the policy wants it to give up the hart.
+pub fn tick(task: &mut TaskStruct) -> bool {
+    if task.id.is_none() {
+        // The idle task always makes way.
+        return true;
+    }
+    task.cpu_ticks += 1;
+    let hart_id = task.hart;
+    SCHEDULER.with(|scheduler| {
+        let resched = hart(scheduler, hart_id).run_queue.tick(task);
+        // A real-time job out of budget waits for its next release.
+        if let Some(release) = task.rt.map(|rt| rt.release)
+            && release > get_current_tick()
+        {
+            start_sleep(scheduler, task, timer::tick_mtime(release));
+        }
+        resched
+    })
+}
+
+fn pick_next(scheduler: &mut Scheduler, hart_id: usize) {
+    // The task this hart ran so far goes back to the run queue, or is filed
+    // away if it cannot run anymore.
+    let prev = match scheduler.harts[hart_id].as_mut() {
+        Some(h) => h.current.take(),
+        None => panic!("Hart is not initialized\n"),
     };
-    let pool = match scheduler.pool.as_mut() {
-        Some(p) => p,
-        None => panic!("Scheduler list should not be None\n"),
+    let was_idle = prev.is_none();
+    if let Some(prev) = prev {
+        put_prev(scheduler, hart_id, prev);
+    }
+    // Only steal once nothing here can run.
+    let next = next_task(scheduler, hart_id).or_else(|| steal(scheduler, hart_id));
+    let hart = match scheduler.harts[hart_id].as_mut() {
+        Some(h) => h,
+        None => panic!("Hart is not initialized\n"),
     };
-    for b in blocked.iter_safe().unwrap() {
-        let is_ready = {
-            let mut guard = b.get_ref().lock();
-            let btask = match guard.value.as_mut() {
-                Some(t) => t,
-                None => continue,
-            };
-            if let Some(sleep_until) = btask.sleep_until {
-                if get_current_tick() >= sleep_until {
-                    btask.state = TaskState::Ready;
-                    btask.sleep_until = None;
-                }
+    let trap_sp = align_stack_ptr(&hart.trap_stack) as u64;
+    let picked = next.and_then(|task| {
+        let picked = task
+            .get_ref()
+            .lock()
+            .value
+            .as_mut()
+            .map(|t| claim(t, hart_id, trap_sp));
+        hart.current = Some(task);
+        picked
+    });
+    match picked {
+        Some((xepc, struct_ptr)) => {
+            if was_idle {
+                timer::resume_tick(hart_id as u64);
             }
-            btask.state == TaskState::Ready
-        };
-        if is_ready {
-            match LinkedList::remove_node_safe(b) {
-                Some(n) => running.push_back_node(n),
-                None => continue,
-            };
+            csr::write_sepc(xepc);
+            csr::write_sscratch(struct_ptr);
+            csr::sstatus_set_pp(PrivilegeMode::User);
+        }
+        None => {
+            // if no task, switch to idle task, which sleeps until the next
+            // timer fires or another hart kicks it.
+            timer::set_one_shot(hart_id as u64, next_expiry());
+            csr::write_sepc(hart.idle_task.xepc);
+            csr::write_sscratch(&hart.idle_task as *const TaskStruct as u64);
+            csr::sstatus_set_pp(PrivilegeMode::Supervisor);
         }
     }
-    for r in running.iter_safe().unwrap() {
-        let (xepc, struct_ptr, state) = {
-            let mut guard = r.get_ref().lock();
-            let rtask = match guard.value.as_mut() {
-                Some(t) => t,
-                None => continue,
-            };
-            match rtask.state {
-                TaskState::Ready => rtask.state = TaskState::Ready,
-                TaskState::Running => rtask.state = TaskState::Ready,
-                TaskState::Sleeping => rtask.state = TaskState::Sleeping,
-                _ => rtask.state = TaskState::None,
-            }
-            (rtask.xepc, rtask as *const TaskStruct as u64, rtask.state)
-        };
-        let task = match LinkedList::remove_node_safe(r) {
-            Some(n) => n,
-            None => continue,
+}
+
+// File the task that just left `hart_id` by its state. A ready one stays on
+// this hart unless its affinity no longer allows it.
+fn put_prev(scheduler: &Scheduler, hart_id: usize, task: TaskNode) {
+    let (state, stays) = {
+        let mut guard = task.get_ref().lock();
+        let t = match guard.value.as_mut() {
+            Some(t) => t,
+            None => return,
         };
-        match state {
-            TaskState::Ready | TaskState::Running => {
-                csr::write_sepc(xepc);
-                csr::write_sscratch(struct_ptr);
-                csr::sstatus_set_pp(PrivilegeMode::User);
-                waiting.push_back_node(task);
-                return;
+        t.on_cpu = false;
+        match t.state {
+            TaskState::Ready | TaskState::Running => t.state = TaskState::Ready,
+            TaskState::Sleeping | TaskState::Blocked => {
+                hart(scheduler, hart_id).run_queue.on_block(t);
             }
-            TaskState::Sleeping => {
-                blocked.push_back_node(task);
-            }
-            _ => {
-                pool.push_back_node(task);
+            TaskState::Zombie if t.parent.is_some() => {}
+            _ => t.state = TaskState::None,
+        }
+        if matches!(t.state, TaskState::Zombie | TaskState::None) {
+            // The task is gone, release its memory and hart time right away.
+            t.address_space = None;
+            if let Some(rt) = t.rt.take() {
+                hart(scheduler, hart_id).run_queue.retire(&rt);
             }
+        }
+        (t.state, t.affinity & (1 << hart_id) != 0)
+    };
+    match state {
+        TaskState::Ready if stays => hart(scheduler, hart_id).run_queue.enqueue(task),
+        TaskState::Ready => enqueue(scheduler, task),
+        TaskState::Sleeping | TaskState::Blocked => {
+            list(&scheduler.blocked_list).push_back_node(task);
+        }
+        TaskState::Zombie => {
+            list(&scheduler.zombie_list).push_back_node(task);
+        }
+        _ => {
+            list(&scheduler.pool).push_back_node(task);
+        }
+    }
+}
+
+// Next ready task from this hart's run queue.
+fn next_task(scheduler: &Scheduler, hart_id: usize) -> Option<TaskNode> {
+    let run_queue = &hart(scheduler, hart_id).run_queue;
+    while let Some(task) = run_queue.pick_next() {
+        let ready = task
+            .get_ref()
+            .lock()
+            .value
+            .as_ref()
+            .is_some_and(|t| t.state == TaskState::Ready);
+        if ready {
+            return Some(task);
+        }
+        put_prev(scheduler, hart_id, task);
+    }
+    None
+}
+
+// Take a ready task queued on another hart, so an idle hart does not sit next
+// to a busy one.
+fn steal(scheduler: &Scheduler, hart_id: usize) -> Option<TaskNode> {
+    for (victim_id, victim) in scheduler.harts.iter().enumerate() {
+        let victim = match victim {
+            Some(v) if victim_id != hart_id => v,
+            _ => continue,
         };
+        let found =
+            victim.run_queue.tasks().find(|task| {
+                task.get_ref().lock().value.as_ref().is_some_and(|t| {
+                    t.state == TaskState::Ready && t.affinity & (1 << hart_id) != 0
+                })
+            });
+        if let Some(task) = found {
+            return victim.run_queue.dequeue(task);
+        }
     }
-    // if no task, switch to idle task.
-    csr::write_sepc(scheduler.idle_task.as_ref().unwrap().xepc);
-    csr::write_sscratch(scheduler.idle_task.as_ref().unwrap() as *const TaskStruct as u64);
-    csr::sstatus_set_pp(PrivilegeMode::Supervisor);
+    None
 }
 
-pub fn get_task_state(id: u64) -> TaskState {
-    let scheduler = unsafe { &mut *SCHEDULER.inner.get() };
-    let blocked = scheduler.blocked_list.as_mut();
-    let waiting = scheduler.waiting_list.as_mut();
-    let running = scheduler.running_list.as_mut();
-    if let Some(blist) = blocked {
-        if let Some(iter) = blist.iter() {
-            for task in iter {
-                let guard = task.get_ref().lock();
-                if let Some(t) = guard.value.as_ref() {
-                    if let Some(tid) = t.id {
-                        if tid == id {
-                            // return t.state;
-                            return TaskState::Sleeping;
-                        }
-                    }
-                }
+// Run `f` on the live task `id`, wherever the scheduler keeps it.
+fn with_task<R>(id: u64, f: impl FnOnce(&mut TaskStruct) -> R) -> Option<R> {
+    SCHEDULER.with(|scheduler| {
+        let task = find_task(scheduler, id)?;
+        task.get_ref().lock().value.as_mut().map(f)
+    })
+}
+
+fn find_task(scheduler: &Scheduler, id: u64) -> Option<TaskNode> {
+    let is_id = |task: &TaskNode| {
+        task.get_ref()
+            .lock()
+            .value
+            .as_ref()
+            .is_some_and(|t| t.id == Some(id))
+    };
+    live_tasks(scheduler).find(is_id)
+}
+
+// Every task that has not exited: blocked, running or queued on a hart.
+fn live_tasks(scheduler: &Scheduler) -> impl Iterator<Item = TaskNode> + '_ {
+    let harts = scheduler.harts.iter().flatten();
+    list(&scheduler.blocked_list)
+        .iter()
+        .into_iter()
+        .flatten()
+        .chain(
+            harts
+                .clone()
+                .filter_map(|h| h.current.as_ref().map(|t| t.clone())),
+        )
+        .chain(harts.flat_map(|h| h.run_queue.tasks()))
+}
+
+/// Run `f` on every task that has not been reaped yet: running, queued,
+/// blocked or zombie. Returns how many there were.
+pub fn for_each_task(mut f: impl FnMut(&TaskStruct)) -> usize {
+    SCHEDULER.with(|scheduler| {
+        let tasks =
+            live_tasks(scheduler).chain(list(&scheduler.zombie_list).iter().into_iter().flatten());
+        let mut count = 0;
+        for task in tasks {
+            if let Some(t) = task.get_ref().lock().value.as_ref() {
+                f(t);
+                count += 1;
             }
         }
+        count
+    })
+}
+
+pub fn get_task_state(id: u64) -> TaskState {
+    with_task(id, |t| t.state).unwrap_or(TaskState::None)
+}
+
+/// Make the blocked task `id` ready again and queue it on a hart right
+/// away. Returns false if it is gone or was not blocked.
+pub fn wake_task(id: u64) -> bool {
+    wake(id, TaskState::Blocked)
+}
+
+// Timer callback ending a sleep. `data` is the reference to the task's node
+// that `start_sleep` handed over, so there is no task to look for.
+fn wake_sleeper(data: u64) {
+    let task = unsafe { TaskNode::from_raw(data as *const ()) };
+    SCHEDULER.with(|scheduler| wake_node(scheduler, task, TaskState::Sleeping));
+}
+
+fn wake(id: u64, from: TaskState) -> bool {
+    SCHEDULER.with(|scheduler| match find_task(scheduler, id) {
+        Some(task) => wake_node(scheduler, task, from),
+        None => false,
+    })
+}
+
+fn wake_node(scheduler: &Scheduler, task: TaskNode, from: TaskState) -> bool {
+    let queue = {
+        let mut guard = task.get_ref().lock();
+        let t = match guard.value.as_mut() {
+            Some(t) if t.state == from => t,
+            _ => return false,
+        };
+        t.state = TaskState::Ready;
+        // One still on its hart is queued when it leaves it.
+        if !t.on_cpu {
+            hart(scheduler, t.hart).run_queue.on_wake(t);
+        }
+        !t.on_cpu
+    };
+    if !queue {
+        return true;
     }
-    if let Some(wlist) = waiting {
-        if let Some(iter) = wlist.iter() {
-            for task in iter {
-                let guard = task.get_ref().lock();
-                if let Some(t) = guard.value.as_ref() {
-                    if let Some(tid) = t.id {
-                        if tid == id {
-                            return t.state;
-                        }
-                    }
-                }
+    if let Some(n) = LinkedList::remove_node_safe(task) {
+        enqueue(scheduler, n);
+    }
+    true
+}
+
+/// Turn `task` into a zombie holding `code` and wake whoever waits for it.
+/// Its children are left with no one to wait for them: the ones that
+/// already exited are freed, the others will not be kept as zombies.
+pub fn task_exit(task: &mut TaskStruct, code: i32) {
+    // Under the lock, so `wait_for_exit` either sees the zombie or is
+    // already queued when the waiters are woken.
+    SCHEDULER.with(|scheduler| {
+        task.state = TaskState::Zombie;
+        task.exit_code = code;
+        if let Some(id) = task.id {
+            orphan_children(scheduler, id);
+        }
+    });
+    task.exit_waiters.wake_all();
+}
+
+fn orphan_children(scheduler: &mut Scheduler, parent: u64) {
+    for child in live_tasks(scheduler) {
+        if let Some(t) = child.get_ref().lock().value.as_mut()
+            && t.parent == Some(parent)
+        {
+            t.parent = None;
+        }
+    }
+    while reap_zombie(scheduler, parent, None).is_some() {}
+}
+
+/// Block `task` until its child `id` exits. Returns false if it has no such
+/// child to wait for. A child that already exited but is not reaped yet
+/// does not block the caller.
+pub fn wait_for_exit(task: &mut TaskStruct, id: u64) -> bool {
+    if task.id == Some(id) {
+        return false;
+    }
+    with_task(id, |child| match child.state {
+        _ if child.parent != task.id => false,
+        TaskState::None => false,
+        TaskState::Zombie => true,
+        _ => child.exit_waiters.wait(task).is_some(),
+    })
+    .unwrap_or(false)
+}
+
+/// Free the exited child `id` of the task `parent` and return its exit
+/// code, or `None` if it is not a zombie child of `parent`.
+pub fn reap(parent: u64, id: u64) -> Option<i32> {
+    SCHEDULER.with(|scheduler| reap_zombie(scheduler, parent, Some(id)))
+}
+
+// Reap the zombie child `id` of `parent`, or any zombie child of it for
+// `None`.
+fn reap_zombie(scheduler: &mut Scheduler, parent: u64, id: Option<u64>) -> Option<i32> {
+    let zombie = scheduler.zombie_list.as_mut()?;
+    let pool = scheduler.pool.as_mut()?;
+    for z in zombie.iter_safe()? {
+        let code = {
+            let mut guard = z.get_ref().lock();
+            let ztask = match guard.value.as_mut() {
+                Some(t) => t,
+                None => continue,
+            };
+            if ztask.parent != Some(parent) || id.is_some_and(|id| ztask.id != Some(id)) {
+                continue;
             }
+            ztask.state = TaskState::None;
+            ztask.exit_code
+        };
+        if let Some(n) = LinkedList::remove_node_safe(z) {
+            pool.push_back_node(n);
         }
+        return Some(code);
+    }
+    None
+}
+
+/// Restrict `task` to the harts in `mask`, returning the previous mask. If
+/// it is running on a hart it may no longer use, the next `schedule` moves
+/// it away. Real-time tasks stay on the hart that admitted them.
+pub fn set_affinity(task: &mut TaskStruct, mask: u64) -> Option<u64> {
+    let mask = mask & ALL_HARTS;
+    if mask == 0 || task.rt.is_some() {
+        return None;
+    }
+    Some(SCHEDULER.with(|_| core::mem::replace(&mut task.affinity, mask)))
+}
+
+/// Trace the syscalls of the task `id`, or `task` itself when `id` is 0 or
+/// its own id, following `mode`. Returns the previous mode, or `None` for an
+/// unknown task.
+pub fn set_trace(task: &mut TaskStruct, id: u64, mode: TraceMode) -> Option<TraceMode> {
+    if id == 0 || task.id == Some(id) {
+        return Some(SCHEDULER.with(|_| core::mem::replace(&mut task.trace, mode)));
     }
-    if let Some(rlist) = running {
-        if let Some(iter) = rlist.iter() {
-            for task in iter {
-                let guard = task.get_ref().lock();
-                if let Some(t) = guard.value.as_ref() {
-                    if let Some(tid) = t.id {
-                        if tid == id {
-                            return t.state;
-                        }
-                    }
+    with_task(id, |t| core::mem::replace(&mut t.trace, mode))
+}
+
+/// Move the task `id`, or `task` itself when `id` is 0 or its own id, to
+/// `priority`, with turns of `time_slice` ticks or the level's default when
+/// it is 0. Returns false for an unknown task or priority.
+pub fn set_priority(task: &mut TaskStruct, id: u64, priority: usize, time_slice: u64) -> bool {
+    if priority >= PRIORITY_LEVELS {
+        return false;
+    }
+    let time_slice = if time_slice == 0 {
+        default_time_slice(priority)
+    } else {
+        time_slice
+    };
+    let apply = |t: &mut TaskStruct| {
+        t.priority = priority;
+        t.time_slice = time_slice;
+        t.slice_left = t.slice_left.min(time_slice);
+    };
+    if id == 0 || task.id == Some(id) {
+        SCHEDULER.with(|_| apply(task));
+        return true;
+    }
+    SCHEDULER.with(|scheduler| {
+        let other = match find_task(scheduler, id) {
+            Some(t) => t,
+            None => return false,
+        };
+        if let Some(t) = other.get_ref().lock().value.as_mut() {
+            apply(t);
+        }
+        // A queued task has to be queued again where the policy now wants it.
+        for hart in scheduler.harts.iter().flatten() {
+            if hart.run_queue.tasks().any(|t| Arc::ptr_eq(&t, &other)) {
+                if let Some(t) = hart.run_queue.dequeue(other) {
+                    hart.run_queue.enqueue(t);
                 }
+                break;
             }
         }
+        true
+    })
+}
+
+/// Put `task` to sleep until `mtime` reaches `wakeup`. Returns false if no
+/// timer could be set up, leaving the task ready. The caller must
+/// reschedule.
+pub fn sleep_until(task: &mut TaskStruct, wakeup: u64) -> bool {
+    SCHEDULER.with(|scheduler| start_sleep(scheduler, task, wakeup))
+}
+
+// `sleep_until` for callers that hold the scheduler lock already. `task` has
+// to be the one running on its hart. The timer keeps a reference to its
+// node: only the timer ends a sleep, so the node still holds this task when
+// it fires.
+fn start_sleep(scheduler: &Scheduler, task: &mut TaskStruct, wakeup: u64) -> bool {
+    let node = match hart(scheduler, task.hart).current.as_ref() {
+        Some(node) if holds(node, task) => node.clone(),
+        _ => return false,
+    };
+    // Asleep before the timer exists, so it cannot fire too early to wake it.
+    task.state = TaskState::Sleeping;
+    let data = node.into_raw() as u64;
+    if add_timer(wakeup, None, wake_sleeper, data).is_none() {
+        drop(unsafe { TaskNode::from_raw(data as *const ()) });
+        task.state = TaskState::Ready;
+        return false;
     }
-    TaskState::None
+    true
+}
+
+fn holds(node: &TaskNode, task: &TaskStruct) -> bool {
+    node.get_ref()
+        .lock()
+        .value
+        .as_ref()
+        .is_some_and(|t| core::ptr::eq(t, task))
+}
+
+/// End the current job of the real-time `task`, which sleeps until its next
+/// release. Returns false if `task` is not a real-time task.
+pub fn wait_period(task: &mut TaskStruct) -> bool {
+    SCHEDULER.with(|scheduler| {
+        let rt = match task.rt.as_mut() {
+            Some(rt) => rt,
+            None => return false,
+        };
+        let now = get_current_tick();
+        let release = rt.next_release(now);
+        rt.start_job(release);
+        if release > now {
+            start_sleep(scheduler, task, timer::tick_mtime(release));
+        }
+        true
+    })
 }
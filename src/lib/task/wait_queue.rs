@@ -0,0 +1,75 @@
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+
+use crate::mutex::Lock;
+use crate::mutex::SpinLock;
+use crate::task::scheduler;
+use crate::task::{TaskState, TaskStruct};
+
+/// Tasks parked until some event happens, such as a child exiting.
+///
+/// Waiters are kept by id, so waking a task that is already gone does
+/// nothing. A woken task only becomes `Ready`; it has to check again whether
+/// what it waited for actually happened.
+pub struct WaitQueue {
+    lock: SpinLock,
+    waiters: UnsafeCell<VecDeque<u64>>,
+}
+
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            lock: SpinLock::new(),
+            waiters: UnsafeCell::new(VecDeque::new()),
+        }
+    }
+
+    /// Block `task` until the queue is woken. The caller must reschedule.
+    pub fn wait(&self, task: &mut TaskStruct) -> Option<()> {
+        let id = task.id?;
+        self.lock.lock();
+        let waiters = unsafe { &mut *self.waiters.get() };
+        let queued = waiters.try_reserve(1).is_ok();
+        if queued {
+            waiters.push_back(id);
+            task.state = TaskState::Blocked;
+        }
+        self.lock.unlock();
+        if queued { Some(()) } else { None }
+    }
+
+    /// Wake the task that has waited longest. Returns false if none was
+    /// still blocked.
+    pub fn wake_one(&self) -> bool {
+        loop {
+            self.lock.lock();
+            let id = unsafe { (*self.waiters.get()).pop_front() };
+            self.lock.unlock();
+            match id {
+                // Outside the queue lock, waking takes the scheduler lock.
+                Some(id) if scheduler::wake_task(id) => return true,
+                Some(_) => continue,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock.lock();
+        let empty = unsafe { (*self.waiters.get()).is_empty() };
+        self.lock.unlock();
+        empty
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,23 +1,57 @@
+pub mod timer_queue;
+
 use crate::csr;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-const CLINT_BASE: u64 = 0x200_0000;
-const _MSIP_BASE: u64 = CLINT_BASE;
+pub const CLINT_BASE: u64 = 0x200_0000;
+const MSIP_BASE: u64 = CLINT_BASE;
 const MTIMECMP_BASE: u64 = CLINT_BASE + 0x4000;
+#[cfg(not(feature = "sbi"))]
 const MTIME_BASE: u64 = CLINT_BASE + 0xBFF8;
 
-static INTERRUPT_INTERVAL: u64 = 10000;
-
+// `mtime` ticks between two scheduler ticks.
 const TICK_INTERVAL: u64 = 10000;
-static CURRENT_TICK: AtomicU64 = AtomicU64::new(0);
 static INITIAL_MTIME: AtomicU64 = AtomicU64::new(0);
 
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+pub const NS_PER_MS: u64 = 1_000_000;
+// What QEMU's virt machine uses when the device tree does not say.
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE_FREQUENCY);
+
+// With Sstc every hart has its own `stimecmp` and takes supervisor timer
+// interrupts directly, instead of going through M-mode and the CLINT.
+static SSTC: AtomicBool = AtomicBool::new(false);
+
+// Under an SBI firmware the CLINT belongs to M-mode, S-mode reads the same
+// counter through the `time` CSR.
+#[cfg(not(feature = "sbi"))]
 macro_rules! read_mtime {
     () => {
         unsafe { core::ptr::read_volatile(MTIME_BASE as *const u64) }
     };
 }
 
+#[cfg(feature = "sbi")]
+macro_rules! read_mtime {
+    () => {
+        csr::read_time()
+    };
+}
+
+// When the periodic tick is next due on each hart. Kicks from other harts
+// and early wakeups interrupt a hart too, but only the tick is charged to
+// the task running there.
+static NEXT_TICK: [AtomicU64; crate::smp::MAX_HARTS] =
+    [const { AtomicU64::new(0) }; crate::smp::MAX_HARTS];
+static TICK_PENDING: [AtomicBool; crate::smp::MAX_HARTS] =
+    [const { AtomicBool::new(false) }; crate::smp::MAX_HARTS];
+
+// What the firmware was last asked for on each hart, it cannot be read back.
+#[cfg(feature = "sbi")]
+static NEXT_TIMER: [AtomicU64; crate::smp::MAX_HARTS] =
+    [const { AtomicU64::new(u64::MAX) }; crate::smp::MAX_HARTS];
+
 macro_rules! write_mtimecmp {
     ($hart_id: expr, $value: expr) => {
         let mtimecmp_address = MTIMECMP_BASE + $hart_id * 8;
@@ -27,25 +61,187 @@ macro_rules! write_mtimecmp {
     };
 }
 
+/// Ticks since hart 0 started its timer. Read from `mtime` rather than
+/// counted, so it stays right while harts sleep through idle periods.
 pub fn get_current_tick() -> u64 {
-    CURRENT_TICK.load(Ordering::Acquire)
+    (read_mtime!() - INITIAL_MTIME.load(Ordering::Acquire)) / TICK_INTERVAL
 }
 
-pub fn set_current_tick(tick: u64) {
-    CURRENT_TICK.store(tick, Ordering::Release);
+pub fn get_tick_mtime() -> u64 {
+    tick_mtime(get_current_tick())
 }
 
-pub fn get_tick_mtime() -> u64 {
-    INITIAL_MTIME.load(Ordering::Acquire) + CURRENT_TICK.load(Ordering::Acquire) * TICK_INTERVAL
+/// `mtime` at which `tick` starts, or `u64::MAX` for ticks too far out to
+/// ever come.
+pub fn tick_mtime(tick: u64) -> u64 {
+    INITIAL_MTIME
+        .load(Ordering::Acquire)
+        .saturating_add(tick.saturating_mul(TICK_INTERVAL))
 }
 
-pub fn timer_init() {
-    let hart_id = csr::read_mhartid();
+pub fn read_time() -> u64 {
+    read_mtime!()
+}
+
+/// Use `hz` instead of the default timebase, e.g. as read from the device
+/// tree.
+pub fn set_timebase_frequency(hz: u64) {
+    if hz > 0 {
+        TIMEBASE_FREQUENCY.store(hz, Ordering::Release);
+    }
+}
+
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Acquire)
+}
+
+pub fn mtime_to_ns(mtime: u64) -> u64 {
+    (mtime as u128 * NS_PER_SEC as u128 / timebase_frequency() as u128) as u64
+}
+
+// Rounded up, so a sleep never ends early.
+pub fn ns_to_mtime(ns: u64) -> u64 {
+    let mtime = (ns as u128 * timebase_frequency() as u128).div_ceil(NS_PER_SEC as u128);
+    mtime.min(u64::MAX as u128) as u64
+}
+
+/// Nanoseconds since hart 0 started its timer. Never goes backwards.
+pub fn monotonic_ns() -> u64 {
+    mtime_to_ns(read_mtime!() - INITIAL_MTIME.load(Ordering::Acquire))
+}
+
+/// Use the Sstc extension from now on. Call before `timer_init`.
+pub fn enable_sstc() {
+    SSTC.store(true, Ordering::Release);
+}
+
+pub fn has_sstc() -> bool {
+    SSTC.load(Ordering::Acquire)
+}
+
+// `hart_id` must be the calling hart with Sstc or SBI, `stimecmp` is a CSR
+// and the SBI call programs the caller's timer.
+fn set_timer(hart_id: u64, value: u64) {
+    if has_sstc() {
+        csr::write_stimecmp(value);
+    } else {
+        #[cfg(feature = "sbi")]
+        {
+            NEXT_TIMER[hart_id as usize].store(value, Ordering::Relaxed);
+            let _ = crate::sbi::set_timer(value);
+        }
+        #[cfg(not(feature = "sbi"))]
+        {
+            write_mtimecmp!(hart_id, value);
+        }
+    }
+}
+
+fn get_timer(hart_id: u64) -> u64 {
+    if has_sstc() {
+        csr::read_stimecmp()
+    } else {
+        #[cfg(feature = "sbi")]
+        {
+            NEXT_TIMER[hart_id as usize].load(Ordering::Relaxed)
+        }
+        #[cfg(not(feature = "sbi"))]
+        {
+            let mtimecmp_address = MTIMECMP_BASE + hart_id * 8;
+            unsafe { core::ptr::read_volatile(mtimecmp_address as *const u64) }
+        }
+    }
+}
+
+/// Stop the periodic tick on this idle hart and only interrupt it at
+/// `mtime` `wakeup`, or never if there is nothing to wake up for.
+pub fn set_one_shot(hart_id: u64, wakeup: Option<u64>) {
+    set_timer(hart_id, wakeup.unwrap_or(u64::MAX));
+}
+
+/// Make sure this hart takes a timer interrupt no later than `mtime`
+/// `wakeup`, for sleeps that end between two ticks.
+pub fn arm_wakeup(hart_id: u64, wakeup: u64) {
+    if wakeup < get_timer(hart_id) {
+        set_timer(hart_id, wakeup);
+    }
+}
+
+/// Make sure this hart wakes up for the next kernel timer even when it is
+/// due before the tick. Call after firing the expired ones: the interrupt
+/// handlers only re-arm the tick.
+pub fn arm_next_timer(hart_id: u64) {
+    let tick = NEXT_TICK[hart_id as usize].load(Ordering::Relaxed);
+    let next = timer_queue::next_expiry().map_or(tick, |expiry| expiry.min(tick));
+    arm_wakeup(hart_id, next);
+}
+
+/// Bring back the periodic tick once this hart has work again, still waking
+/// up earlier for a kernel timer due before it.
+pub fn resume_tick(hart_id: u64) {
+    let next = read_mtime!() + TICK_INTERVAL;
+    NEXT_TICK[hart_id as usize].store(next, Ordering::Relaxed);
+    set_timer(hart_id, next);
+    arm_next_timer(hart_id);
+}
+
+// Note a timer interrupt at `now` on `hart_id`, and return when the timer
+// should fire next: the tick, unless this one was it.
+fn advance_tick(hart_id: u64, now: u64) -> u64 {
+    let next = &NEXT_TICK[hart_id as usize];
+    if now >= next.load(Ordering::Relaxed) {
+        TICK_PENDING[hart_id as usize].store(true, Ordering::Release);
+        next.store(now + TICK_INTERVAL, Ordering::Relaxed);
+    }
+    next.load(Ordering::Relaxed)
+}
+
+/// Whether the periodic tick came on `hart_id` since the last call, as
+/// opposed to a kick or a wakeup between two ticks.
+pub fn take_tick(hart_id: u64) -> bool {
+    TICK_PENDING[hart_id as usize].swap(false, Ordering::AcqRel)
+}
+
+/// Make an idle hart take an interrupt right away, so it looks at its run
+/// queue again. Its M-mode handler, or the SBI firmware, passes the IPI on
+/// as a supervisor software interrupt.
+pub fn kick(hart_id: u64) {
+    #[cfg(feature = "sbi")]
+    let _ = crate::sbi::send_ipi(1 << hart_id, 0);
+    #[cfg(not(feature = "sbi"))]
+    send_ipi(hart_id);
+}
+
+/// Start the tick on `hart_id`, the calling hart.
+#[cfg(feature = "sbi")]
+pub fn timer_init(hart_id: u64) {
+    let cur_time = read_mtime!();
+    // Ticks count from when the boot hart started, the others share its clock.
+    let _ = INITIAL_MTIME.compare_exchange(0, cur_time, Ordering::AcqRel, Ordering::Acquire);
+    NEXT_TICK[hart_id as usize].store(cur_time + TICK_INTERVAL, Ordering::Relaxed);
+    // The firmware enables Sstc for S-mode itself when the hart has it.
+    set_timer(hart_id, cur_time + TICK_INTERVAL);
+}
+
+/// Start the tick on `hart_id`, the calling hart.
+#[cfg(not(feature = "sbi"))]
+pub fn timer_init(hart_id: u64) {
     let cur_time = read_mtime!();
-    INITIAL_MTIME.store(cur_time, Ordering::Release);
-    write_mtimecmp!(hart_id, cur_time + INTERRUPT_INTERVAL);
+    // Ticks count from when the boot hart started, the others share its clock.
+    let _ = INITIAL_MTIME.compare_exchange(0, cur_time, Ordering::AcqRel, Ordering::Acquire);
+    NEXT_TICK[hart_id as usize].store(cur_time + TICK_INTERVAL, Ordering::Relaxed);
+    if has_sstc() {
+        // Let S-mode program its own timer, M-mode stays out of it.
+        csr::write_menvcfg(csr::read_menvcfg() | 1 << csr::MENVCFG_STCE);
+        csr::write_mcounteren(csr::read_mcounteren() | 1 << csr::MCOUNTEREN_TM);
+        csr::write_stimecmp(cur_time + TICK_INTERVAL);
+    } else {
+        write_mtimecmp!(hart_id, cur_time + TICK_INTERVAL);
+        csr::write_mie(csr::read_mie() | 1 << csr::MIE_MTIE);
+    }
     csr::write_mstatus(csr::read_mstatus() | 1 << csr::MSTATUS_MIE);
-    csr::write_mie(csr::read_mie() | 1 << csr::MIE_MTIE);
+    // Kicks from other harts.
+    csr::write_mie(csr::read_mie() | 1 << csr::MIE_MSIE);
 }
 
 pub fn timer_handler() {
@@ -54,8 +250,34 @@ pub fn timer_handler() {
 
     let hart_id = csr::read_mhartid();
     let cur_time = read_mtime!();
-    write_mtimecmp!(hart_id, cur_time + INTERRUPT_INTERVAL);
-    let initial_mtime = INITIAL_MTIME.load(Ordering::Acquire);
-    let delta = cur_time - initial_mtime;
-    set_current_tick(delta / TICK_INTERVAL);
+    // An idle hart programs its next one-shot deadline when it schedules.
+    write_mtimecmp!(hart_id, advance_tick(hart_id, cur_time));
+}
+
+/// Supervisor timer interrupt, taken with Sstc or under an SBI firmware:
+/// re-arm the tick.
+pub fn supervisor_timer_handler(hart_id: u64) {
+    let cur_time = read_mtime!();
+    set_timer(hart_id, advance_tick(hart_id, cur_time));
+}
+
+/// A kick from another hart, pass it on to S-mode.
+pub fn ipi_handler() {
+    clear_ipi(csr::read_mhartid());
+    csr::write_sip(csr::read_sip() | (1 << csr::SIP_SSIP));
+}
+
+/// Raise a machine software interrupt on `hart_id`.
+pub fn send_ipi(hart_id: u64) {
+    let msip_address = MSIP_BASE + hart_id * 4;
+    unsafe {
+        core::ptr::write_volatile(msip_address as *mut u32, 1);
+    }
+}
+
+pub fn clear_ipi(hart_id: u64) {
+    let msip_address = MSIP_BASE + hart_id * 4;
+    unsafe {
+        core::ptr::write_volatile(msip_address as *mut u32, 0);
+    }
 }
@@ -0,0 +1,147 @@
+use alloc::collections::BinaryHeap;
+use core::cell::UnsafeCell;
+use core::cmp::{Ordering, Reverse};
+
+use crate::mutex::Lock;
+use crate::mutex::SpinLock;
+use crate::timer::{arm_wakeup, read_time};
+
+/// Called with the `data` it was registered with.
+pub type TimerFn = fn(data: u64);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    // `mtime` at which it fires next.
+    expires: u64,
+    id: TimerId,
+    // `mtime` ticks between firings, or `None` to fire once.
+    period: Option<u64>,
+    callback: TimerFn,
+    data: u64,
+}
+
+impl Timer {
+    fn key(&self) -> (u64, u64) {
+        (self.expires, self.id.0)
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Pending kernel timers in a min-heap on expiry, so adding one and firing
+/// the next are O(log n).
+///
+/// Callbacks run in S-mode from the supervisor software interrupt that
+/// every timer interrupt raises, never in M-mode, so they may take locks.
+/// No lock is held while they run.
+struct TimerQueue {
+    lock: SpinLock,
+    inner: UnsafeCell<(BinaryHeap<Reverse<Timer>>, u64)>,
+}
+
+unsafe impl Sync for TimerQueue {}
+
+static TIMERS: TimerQueue = TimerQueue {
+    lock: SpinLock::new(),
+    inner: UnsafeCell::new((BinaryHeap::new(), 1)),
+};
+
+impl TimerQueue {
+    // Run `f` on the heap and the next free id.
+    fn with<R>(&self, f: impl FnOnce(&mut BinaryHeap<Reverse<Timer>>, &mut u64) -> R) -> R {
+        self.lock.lock();
+        let (heap, next_id) = unsafe { &mut *self.inner.get() };
+        let result = f(heap, next_id);
+        self.lock.unlock();
+        result
+    }
+}
+
+/// Call `callback(data)` once `mtime` reaches `expires`, and then every
+/// `period` ticks of `mtime` if one is given. Returns `None` if out of
+/// memory. Only callable in S-mode.
+pub fn add_timer(
+    expires: u64,
+    period: Option<u64>,
+    callback: TimerFn,
+    data: u64,
+) -> Option<TimerId> {
+    let id = TIMERS.with(|heap, next_id| {
+        heap.try_reserve(1).ok()?;
+        let id = TimerId(*next_id);
+        *next_id += 1;
+        heap.push(Reverse(Timer {
+            expires,
+            id,
+            period: period.filter(|p| *p > 0),
+            callback,
+            data,
+        }));
+        Some(id)
+    })?;
+    // The next tick may come too late for a timer that expires soon.
+    arm_wakeup(crate::task::scheduler::current_hart() as u64, expires);
+    Some(id)
+}
+
+/// Stop the timer `id`. Returns false if it already fired for the last time
+/// or never existed.
+pub fn cancel_timer(id: TimerId) -> bool {
+    TIMERS.with(|heap, _| {
+        let before = heap.len();
+        heap.retain(|Reverse(timer)| timer.id != id);
+        heap.len() != before
+    })
+}
+
+/// `mtime` at which the next timer fires.
+pub fn next_expiry() -> Option<u64> {
+    TIMERS.with(|heap, _| heap.peek().map(|Reverse(timer)| timer.expires))
+}
+
+/// Fire every timer that expired by now. Periodic ones are put back first,
+/// so a callback may cancel its own timer.
+pub fn run_expired() {
+    loop {
+        let now = read_time();
+        let expired = TIMERS.with(|heap, _| {
+            if heap.peek()?.0.expires > now {
+                return None;
+            }
+            let Reverse(mut timer) = heap.pop()?;
+            let fire = (timer.callback, timer.data);
+            if let Some(period) = timer.period {
+                // Skip periods that went by entirely instead of firing a burst.
+                let missed = (now - timer.expires) / period;
+                timer.expires += (missed + 1) * period;
+                // Popping left room for it.
+                heap.push(Reverse(timer));
+            }
+            Some(fire)
+        });
+        match expired {
+            Some((callback, data)) => callback(data),
+            None => return,
+        }
+    }
+}
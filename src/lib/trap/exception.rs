@@ -47,3 +47,26 @@ macro_rules! __ENABLE_ALL_EXCEPTIONS_BIT {
 }
 
 pub const ENABLE_ALL_EXCEPTIONS: u64 = __ENABLE_ALL_EXCEPTIONS_BIT!();
+
+pub fn exception_name(cause: u64) -> &'static str {
+    match cause {
+        INSTRUCTION_ADDRESS_MISALIGNED => "instruction address misaligned",
+        INSTRUCTION_ACCESS_FAULT => "instruction access fault",
+        ILLEGAL_INSTRUCTION => "illegal instruction",
+        BREAKPOINT => "breakpoint",
+        LOAD_ADDRESS_MISALIGNED => "load address misaligned",
+        LOAD_ACCESS_FAULT => "load access fault",
+        STORE_AMO_ADDRESS_MISALIGNED => "store/AMO address misaligned",
+        STORE_AMO_ACCESS_FAULT => "store/AMO access fault",
+        ENVIRONMENT_CALL_FROM_U_MODE => "environment call from U-mode",
+        ENVIRONMENT_CALL_FROM_S_MODE => "environment call from S-mode",
+        ENVIRONMENT_CALL_FROM_M_MODE => "environment call from M-mode",
+        INSTRUCTION_PAGE_FAULT => "instruction page fault",
+        LOAD_PAGE_FAULT => "load page fault",
+        STORE_AMO_PAGE_FAULT => "store/AMO page fault",
+        DOUBLE_TRAP => "double trap",
+        SOFTWARE_CHECK => "software check",
+        HARDWARE_ERROR => "hardware error",
+        _ => "unknown exception",
+    }
+}
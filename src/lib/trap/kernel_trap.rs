@@ -40,6 +40,10 @@ pub fn kernel_trap() {
             "sd a2, {offset_xcause}(a0)",
             "csrrw a1, mscratch, a0", // a1 holds the original a0 value, a0 and mscratch hold the task_struct pointer
             "sd a1, {offset_a0}(a0)",
+            "ld sp, {offset_kernel_sp}(a0)", // Switch to the machine trap stack
+            "csrr a1, mstatus", // a1 holds the mode the trap came from, MPP
+            "srli a1, a1, {mstatus_mpp}",
+            "andi a1, a1, 0b11",
             "call trap_dispatch",
             "j kernel_trap_return",
             offset_ra = const crate::task::OFFSET_RA,
@@ -75,6 +79,8 @@ pub fn kernel_trap() {
             offset_a7 = const crate::task::OFFSET_A7,
             offset_xepc = const crate::task::OFFSET_XEPC,
             offset_xcause = const crate::task::OFFSET_XCAUSE,
+            offset_kernel_sp = const crate::task::OFFSET_KERNEL_SP,
+            mstatus_mpp = const crate::csr::MSTATUS_MPP,
         );
     }
 }
@@ -5,14 +5,51 @@ pub mod user_trap;
 
 use crate::csr;
 use crate::plic::{UART0_IRQ, plic_claim, plic_complete};
+use crate::riscv::PrivilegeMode;
 use crate::syscall::syscall_handler;
 use crate::task::{TaskState, TaskStruct};
-use crate::timer::timer_handler;
-use crate::uart::{print_integer, print_string, uart_irq_handler, uart_write_buffer_flush};
+use crate::timer::{
+    arm_next_timer, ipi_handler, supervisor_timer_handler, take_tick, timer_handler,
+};
+use crate::uart::{
+    print_hex, print_integer, print_string, uart_irq_handler, uart_write_buffer_flush,
+};
 
+// Fire expired kernel timers, wake up again for the next one, and on the
+// periodic tick let the policy decide whether the task running here has had
+// its turn. A kick or a wakeup between ticks costs the task nothing, it only
+// reschedules for whatever became ready.
+fn timer_tick(task: &mut TaskStruct) {
+    crate::timer::timer_queue::run_expired();
+    let hart_id = crate::task::scheduler::current_hart() as u64;
+    arm_next_timer(hart_id);
+    // A running task is ready already, unless the tick put it to sleep, like
+    // a real-time task out of budget.
+    if !take_tick(hart_id) || crate::task::scheduler::tick(task) {
+        crate::task::scheduler::schedule();
+    }
+}
+
+fn print_fault(task: &TaskStruct) {
+    print_string(exception::exception_name(task.xcause));
+    print_string(" (cause ");
+    print_hex(task.xcause);
+    print_string(", stval ");
+    print_hex(csr::read_stval());
+    print_string(", sepc ");
+    print_hex(task.xepc);
+    print_string(", ra ");
+    print_hex(task.ra);
+    print_string(")\n");
+}
+
+/// Handle the trap `cur_task_struct` took. `prev_mode` is the mode it came
+/// from, MPP for traps into M-mode and SPP for traps into S-mode, so a fault
+/// only counts as the task's own when it was running in U-mode.
 #[unsafe(no_mangle)]
-pub fn trap_dispatch(cur_task_struct: &mut TaskStruct) {
+pub fn trap_dispatch(cur_task_struct: &mut TaskStruct, prev_mode: u64) {
     let cause = cur_task_struct.xcause;
+    let from_user_mode = PrivilegeMode::from(prev_mode) == PrivilegeMode::User;
     match cause {
         interrupt::MACHINE_TIMER_INTERRUPT => {
             // print_string("==========================================\n");
@@ -21,26 +58,36 @@ pub fn trap_dispatch(cur_task_struct: &mut TaskStruct) {
             // timer_handler will reassign a supervisor software interrupt
             timer_handler();
         }
+        interrupt::MACHINE_SOFTWARE_INTERRUPT => ipi_handler(),
         interrupt::SUPERVISOR_SOFTWARE_INTERRUPT => {
             // print_string("==========================================\n");
             // print_string("Triggered a supervisor software interrupt!\n");
             // print_string("==========================================\n");
-            cur_task_struct.state = TaskState::Ready;
-            crate::task::scheduler::schedule();
+            timer_tick(cur_task_struct);
 
             // let next_task_struct = scheduler();
             // csr::write_sepc(next_task_struct.xepc);
             // csr::write_sscratch(next_task_struct as *const TaskStruct as u64);
             csr::write_sip(csr::read_sip() & !(1 << csr::SIP_SSIP));
         }
+        interrupt::SUPERVISOR_TIMER_INTERRUPT => {
+            // Sstc and SBI firmware deliver the tick straight to S-mode.
+            supervisor_timer_handler(crate::task::scheduler::current_hart() as u64);
+            timer_tick(cur_task_struct);
+        }
         interrupt::SUPERVISOR_EXTERNAL_INTERRUPT => {
-            let irq_id = plic_claim();
+            let hart = crate::task::scheduler::current_hart();
+            let irq_id = plic_claim(hart);
             match irq_id {
                 UART0_IRQ => uart_irq_handler(),
                 _ => print_string("not implement irq\n"),
             }
             if irq_id > 0 {
-                plic_complete(irq_id);
+                plic_complete(hart, irq_id);
+            }
+            // An idle hart runs whatever the interrupt woke up right away.
+            if cur_task_struct.id.is_none() {
+                crate::task::scheduler::schedule();
             }
         }
         exception::ENVIRONMENT_CALL_FROM_U_MODE => {
@@ -51,19 +98,19 @@ pub fn trap_dispatch(cur_task_struct: &mut TaskStruct) {
             // csr::write_sepc(next_task_struct.xepc);
             // csr::write_sscratch(next_task_struct as *const TaskStruct as u64);
         }
+        _ if cause & interrupt::INTERRUPT_BIT == 0 && from_user_mode => {
+            // A fault in a task only takes that task down.
+            print_string("Task ");
+            print_integer(cur_task_struct.id.unwrap_or(0));
+            print_string(" killed: ");
+            print_fault(cur_task_struct);
+            crate::task::scheduler::task_exit(cur_task_struct, -1);
+            crate::task::scheduler::schedule();
+        }
         _ => {
-            // Resume the current task
-            print_string("<< ");
-            print_integer(cur_task_struct.xcause);
-            print_string(", ");
-            print_integer(cur_task_struct.xepc);
-            print_string(", ");
-            print_integer(cur_task_struct.ra);
-            print_string(" >>");
-            panic!("");
-            // cur_task_struct.state = TaskState::Running;
-            // csr::write_sepc(cur_task_struct.xepc);
-            // csr::write_sscratch(cur_task_struct as *const TaskStruct as u64);
+            print_string("Kernel trap: ");
+            print_fault(cur_task_struct);
+            panic!("Unhandled trap in kernel");
         }
     }
     // Only flush UART buffer for non-m-mode timer interrupts.
@@ -71,7 +118,8 @@ pub fn trap_dispatch(cur_task_struct: &mut TaskStruct) {
     // the timer interrupt handler would also try to acquire the same lock to flush the buffer,
     // causing the interrupt handler to spin (busy wait) until the lock is released.
     // To avoid this potential deadlock/spin, we skip flushing in m-mode timer interrupts.
-    if cause != interrupt::MACHINE_TIMER_INTERRUPT {
+    if cause != interrupt::MACHINE_TIMER_INTERRUPT && cause != interrupt::MACHINE_SOFTWARE_INTERRUPT
+    {
         uart_write_buffer_flush();
     }
 }
@@ -1,4 +1,8 @@
+// Both halves live in the trampoline page, which stays supervisor-executable
+// in the user view, so they can switch between the task's and the kernel's
+// address space.
 #[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.trampoline")]
 pub fn user_trap() {
     unsafe {
         core::arch::asm! (
@@ -40,6 +44,13 @@ pub fn user_trap() {
             "sd a2, {offset_xcause}(a0)",
             "csrrw a1, sscratch, a0", // a1 holds the original a0 value, a0 and sscratch hold the task_struct pointer
             "sd a1, {offset_a0}(a0)",
+            "ld a1, {offset_kernel_satp}(a0)", // Switch to the kernel address space
+            "csrw satp, a1",
+            "sfence.vma zero, zero",
+            "ld sp, {offset_kernel_sp}(a0)", // and to the kernel trap stack
+            "csrr a1, sstatus", // a1 holds the mode the trap came from, SPP
+            "srli a1, a1, {sstatus_spp}",
+            "andi a1, a1, 0b1",
             "call trap_dispatch",
             "j user_trap_return",
             offset_ra = const crate::task::OFFSET_RA,
@@ -75,16 +86,23 @@ pub fn user_trap() {
             offset_a7 = const crate::task::OFFSET_A7,
             offset_xepc = const crate::task::OFFSET_XEPC,
             offset_xcause = const crate::task::OFFSET_XCAUSE,
+            offset_kernel_satp = const crate::task::OFFSET_KERNEL_SATP,
+            offset_kernel_sp = const crate::task::OFFSET_KERNEL_SP,
+            sstatus_spp = const crate::csr::SSTATUS_SPP,
         );
     }
 }
 
 #[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.trampoline")]
 pub fn user_trap_return() {
     unsafe {
         core::arch::asm! (
             // Restore all general-purpose registers and return to the interrupt or exception.
             "csrr a7, sscratch",
+            "ld a6, {offset_satp}(a7)", // Switch to the next task's address space
+            "csrw satp, a6",
+            "sfence.vma zero, zero",
             "ld ra, {offset_ra}(a7)",
             "ld sp, {offset_sp}(a7)",
             "ld gp, {offset_gp}(a7)",
@@ -148,6 +166,7 @@ pub fn user_trap_return() {
             offset_a5 = const crate::task::OFFSET_A5,
             offset_a6 = const crate::task::OFFSET_A6,
             offset_a7 = const crate::task::OFFSET_A7,
+            offset_satp = const crate::task::OFFSET_SATP,
         );
     }
 }
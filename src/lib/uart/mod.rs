@@ -1,15 +1,20 @@
 use crate::mutex::Lock;
 use crate::mutex::SpinLock;
+use crate::task::TaskStruct;
+use crate::task::wait_queue::WaitQueue;
 use core::ptr;
 
-const UART: usize = 0x10000000;
+pub const UART: usize = 0x10000000;
+#[cfg(not(feature = "sbi"))]
 const UART_THR: *mut u8 = (UART + 0b000) as *mut u8;
 const UART_RHR: *mut u8 = (UART + 0b000) as *mut u8;
 const UART_IER: *mut u8 = (UART + 0b001) as *mut u8;
 const _UART_ISR: *mut u8 = (UART + 0b010) as *mut u8;
 const _UART_LCR: *mut u8 = (UART + 0b011) as *mut u8;
 const UART_LSR: *mut u8 = (UART + 0b101) as *mut u8;
+#[cfg(not(feature = "sbi"))]
 const LSR_THR_EMPTY_BIT: u8 = 5;
+#[cfg(not(feature = "sbi"))]
 const UART_THR_EMPTY: u8 = 1 << LSR_THR_EMPTY_BIT;
 const IER_DATA_READY_BIT: u8 = 0;
 const UART_RX_ENABLE: u8 = 1 << IER_DATA_READY_BIT;
@@ -22,12 +27,15 @@ static mut UART_WRITE_HEAD: usize = 0;
 static mut UART_WRITE_TAIL: usize = 0;
 static UART_WRITE_LOCK: SpinLock = SpinLock::new();
 
-const UART_READ_BUFFER_SIZE: usize = 256;
+/// Longest line `uart_read` can return, terminator included.
+pub const UART_READ_BUFFER_SIZE: usize = 256;
 static mut UART_READ_BUFFER: [u8; UART_READ_BUFFER_SIZE] = [0; UART_READ_BUFFER_SIZE];
 static mut UART_READ_HEAD: usize = 0;
 static mut UART_READ_TAIL: usize = 0;
 static mut UART_NEWLINE_CNT: isize = 0;
 static UART_READ_LOCK: SpinLock = SpinLock::new();
+// Tasks blocked in `sys_read` until a whole line has been typed.
+static UART_READERS: WaitQueue = WaitQueue::new();
 
 macro_rules! write_reg {
     ($addr: expr, $value: expr) => {
@@ -113,8 +121,22 @@ pub fn uart_read(buffer: *mut u8, len: usize) -> Option<usize> {
     Some(read_len)
 }
 
+// Block `task` until the next line arrives. A line that came in since the
+// caller last looked leaves it ready, so it can simply read again.
+pub fn uart_wait_line(task: &mut TaskStruct) -> Option<()> {
+    UART_READ_LOCK.lock();
+    let result = if unsafe { UART_NEWLINE_CNT } > 0 {
+        Some(())
+    } else {
+        UART_READERS.wait(task)
+    };
+    UART_READ_LOCK.unlock();
+    result
+}
+
 pub fn uart_irq_handler() {
     UART_READ_LOCK.lock();
+    let mut got_line = false;
     unsafe {
         while *UART_LSR & UART_DATA_READY != 0 {
             let byte = ptr::read_volatile(UART_RHR);
@@ -132,14 +154,26 @@ pub fn uart_irq_handler() {
                 UART_READ_TAIL = next_tail;
                 if is_newline {
                     UART_NEWLINE_CNT += 1;
+                    got_line = true;
                 }
                 print_char(store_byte as char);
             }
         }
+        if got_line {
+            UART_READERS.wake_all();
+        }
     }
     UART_READ_LOCK.unlock();
 }
 
+// Through the firmware's console when there is one, it knows where the
+// board's UART is.
+#[cfg(feature = "sbi")]
+pub fn print_char(c: char) {
+    crate::sbi::console_putchar(c as u8);
+}
+
+#[cfg(not(feature = "sbi"))]
 pub fn print_char(c: char) {
     unsafe {
         while (*UART_LSR & UART_THR_EMPTY) == 0 {}
@@ -179,3 +213,16 @@ pub fn print_integerln(num: u64) {
     print_integer(num);
     print_char('\n');
 }
+
+pub fn print_hex(num: u64) {
+    print_string("0x");
+    for shift in (0..16).rev() {
+        let digit = ((num >> (shift * 4)) & 0xf) as u8;
+        let c = if digit < 10 {
+            b'0' + digit
+        } else {
+            b'a' + digit - 10
+        };
+        print_char(c as char);
+    }
+}
@@ -131,6 +131,24 @@ impl<T> LinkedList<T> {
         self.push_front_node(node_arc)
     }
 
+    /// Unlink `node` if it is in this list. `remove_node_safe` takes a node
+    /// off whatever list it is on.
+    pub fn remove_node(
+        &self,
+        node: Arc<Mutex<ListNode<T>, YieldLock>>,
+    ) -> Option<Arc<Mutex<ListNode<T>, YieldLock>>> {
+        let queued = self
+            .iter()
+            .into_iter()
+            .flatten()
+            .any(|n| Arc::ptr_eq(&n, &node));
+        if queued {
+            Self::remove_node_safe(node)
+        } else {
+            None
+        }
+    }
+
     pub fn remove_node_safe(
         node_to_remove: Arc<Mutex<ListNode<T>, YieldLock>>,
     ) -> Option<Arc<Mutex<ListNode<T>, YieldLock>>> {
@@ -1,38 +1,116 @@
+use crate::mm::{PAGE_SIZE, frame};
 use crate::mutex::Lock;
-use crate::mutex::YieldLock;
+use crate::mutex::SpinLock;
+use crate::syscall::sys_alloc;
+use crate::uart::{print_integer, print_string};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use heap::{ALIGNMENT, HEADER_SIZE, Heap, MIN_BLOCK_SIZE};
 
-const HEAP_SIZE: usize = 32 * 1024;
-static HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
-static mut PROGRAM_BREAK: usize = 0;
-static HEAP_LOCK: YieldLock = YieldLock::new();
-const ALIGNMENT: usize = 16;
+// The heap grows from the frame allocator at least this many pages at a time.
+const HEAP_GROW_PAGES: usize = 16;
+
+static mut HEAP: Heap = Heap::empty();
+static mut HEAP_BACKEND: HeapBackend = HeapBackend::Syscall;
+// Every task has its own copy of the heap, so only the kernel, on several
+// harts at once and unable to yield, ever contends for it.
+static HEAP_LOCK: SpinLock = SpinLock::new();
+
+/// Where the heap gets more memory from.
+///
+/// Tasks start from a pristine copy of the globals and so ask the kernel
+/// through `sys_alloc`; the kernel switches itself to the frame allocator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeapBackend {
+    Syscall,
+    Frames,
+}
+
+unsafe fn kernel_heap() -> &'static mut Heap {
+    let heap = &raw mut HEAP;
+    unsafe { &mut *heap }
+}
+
+pub fn set_backend(backend: HeapBackend) {
+    HEAP_LOCK.lock();
+    unsafe {
+        HEAP_BACKEND = backend;
+    }
+    HEAP_LOCK.unlock();
+}
+
+// Hand enough fresh pages to the heap for an `nbytes` request aligned to `align`.
+fn grow(heap: &mut Heap, nbytes: usize, align: usize) -> bool {
+    let worst_case = nbytes + align + HEADER_SIZE + MIN_BLOCK_SIZE;
+    let pages = worst_case.div_ceil(PAGE_SIZE).max(HEAP_GROW_PAGES);
+    let region = match unsafe { HEAP_BACKEND } {
+        HeapBackend::Frames => frame::alloc_contiguous(pages),
+        HeapBackend::Syscall => sys_alloc(pages * PAGE_SIZE).ok().map(|ptr| ptr as usize),
+    };
+    match region {
+        Some(addr) => {
+            unsafe { heap.add_region(addr, pages * PAGE_SIZE) };
+            true
+        }
+        None => false,
+    }
+}
 
 #[inline(never)]
 pub unsafe fn malloc(nbytes: usize) -> Option<*mut u8> {
+    unsafe { malloc_aligned(nbytes, ALIGNMENT) }
+}
+
+#[inline(never)]
+pub unsafe fn malloc_aligned(nbytes: usize, align: usize) -> Option<*mut u8> {
     unsafe {
         HEAP_LOCK.lock();
-        let nbytes = nbytes.max(8);
-        let current_program_break_addr = HEAP.as_ptr() as usize + PROGRAM_BREAK;
-        let remainder = current_program_break_addr % ALIGNMENT;
-        let padding_bytes = if remainder == 0 {
-            0
-        } else {
-            ALIGNMENT - remainder
-        };
-        let aligned_program_break_offset = PROGRAM_BREAK + padding_bytes;
-        if aligned_program_break_offset + nbytes > HEAP_SIZE {
-            HEAP_LOCK.unlock();
-            return None;
+        let heap = kernel_heap();
+        let mut ptr = heap.allocate_aligned(nbytes, align);
+        if ptr.is_none() && grow(heap, nbytes, align) {
+            ptr = heap.allocate_aligned(nbytes, align);
         }
-        let ptr = HEAP.as_ptr().add(aligned_program_break_offset);
-        PROGRAM_BREAK = aligned_program_break_offset + nbytes;
         HEAP_LOCK.unlock();
-        Some(ptr as *mut u8)
+        ptr
+    }
+}
+
+pub unsafe fn free(ptr: *mut u8) {
+    unsafe {
+        HEAP_LOCK.lock();
+        kernel_heap().deallocate(ptr);
+        HEAP_LOCK.unlock();
+    }
+}
+
+/// Backs the `alloc` crate (`Box`, `Vec`, `String`, ...) with the kernel heap.
+pub struct KernelAllocator;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match unsafe { malloc_aligned(layout.size(), layout.align()) } {
+            Some(ptr) => ptr,
+            None => {
+                alloc_error(layout);
+                ptr::null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe { free(ptr) }
     }
 }
 
-pub unsafe fn free(_ptr: *mut u8) {
-    // do nothing now
+// Report the failed request before the `alloc` crate turns the null pointer
+// into a panic, whose message alone does not say what was asked for.
+fn alloc_error(layout: Layout) {
+    print_string("alloc error: size ");
+    print_integer(layout.size() as u64);
+    print_string(", align ");
+    print_integer(layout.align() as u64);
+    print_string("\n");
 }
-// TODO (freelist) pub unsafe fn malloc(nbytes: usize) -> Option<*mut u8>;
-// TODO pub unsafe fn free(ptr: *mut u8);
@@ -45,6 +45,25 @@ impl<T> Arc<T> {
     pub fn ptr_eq(a1: &Arc<T>, a2: &Arc<T>) -> bool {
         a1.ptr == a2.ptr
     }
+
+    // Hand the reference over as a plain pointer, e.g. through a `u64`. It
+    // stays counted until `from_raw` takes it back.
+    pub fn into_raw(self) -> *const () {
+        let this = ManuallyDrop::new(self);
+        this.ptr.as_ptr() as *const ()
+    }
+
+    /// Take back a reference handed over with `into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` on an `Arc<T>`, and be taken back only
+    /// once.
+    pub unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self {
+            ptr: unsafe { NonNull::new_unchecked(ptr as *mut ArcInner<T>) },
+        }
+    }
 }
 
 impl<T> Drop for Arc<T> {
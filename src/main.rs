@@ -9,17 +9,83 @@ use lib::plic::plic_init;
 use lib::riscv::PrivilegeMode;
 use lib::shell;
 use lib::timer::timer_init;
+#[cfg(not(feature = "sbi"))]
 use lib::trap::kernel_trap::kernel_trap;
 use lib::trap::user_trap::user_trap;
 use lib::uart::{print_char, uart_init};
 
 static STARTUP_MESSAGE: &[u8] = include_bytes!("startup_message.txt");
 
+// `_start` passes on what the boot loader left in a0 and a1.
 #[unsafe(no_mangle)]
-fn main() -> ! {
+fn main(hart_id: usize, dtb: usize) -> ! {
     for &c in STARTUP_MESSAGE.iter() {
         print_char(c as char);
     }
+    // Before the frame allocator hands out the memory the device tree is in.
+    read_device_tree(dtb);
+    lib::mm::init();
+    lib::task::scheduler::init();
+    boot(hart_id)
+}
+
+#[cfg(not(feature = "sbi"))]
+fn boot(hart_id: usize) -> ! {
+    machine_init(hart_id);
+    csr::write_mepc(kernel as u64);
+    timer_init(hart_id as u64);
+    // The shared kernel state is ready, let the other harts in.
+    lib::smp::start_secondary_harts();
+    lib::mret!();
+
+    loop {}
+}
+
+// Already in S-mode, the firmware did the M-mode setup.
+#[cfg(feature = "sbi")]
+fn boot(hart_id: usize) -> ! {
+    lib::task::scheduler::init_hart(hart_id);
+    timer_init(hart_id as u64);
+    lib::smp::start_secondary_harts(hart_id, start::secondary_entry as *const () as usize);
+    kernel()
+}
+
+#[cfg(not(feature = "sbi"))]
+fn secondary_main(hart_id: usize) -> ! {
+    machine_init(hart_id);
+    csr::write_mepc(secondary_kernel as u64);
+    timer_init(hart_id as u64);
+    lib::mret!();
+
+    loop {}
+}
+
+#[cfg(feature = "sbi")]
+fn secondary_main(hart_id: usize) -> ! {
+    lib::task::scheduler::init_hart(hart_id);
+    timer_init(hart_id as u64);
+    secondary_kernel()
+}
+
+fn read_device_tree(dtb: usize) {
+    let fdt = match unsafe { lib::fdt::Fdt::from_addr(dtb) } {
+        Some(fdt) => fdt,
+        None => return,
+    };
+    if let Some(hz) = fdt.find_u64("cpus", "timebase-frequency") {
+        lib::timer::set_timebase_frequency(hz);
+    }
+    if fdt.has_isa_extension("sstc") {
+        lib::timer::enable_sstc();
+    }
+    if let Some((base, size)) = fdt.memory() {
+        lib::mm::set_memory_end((base + size) as usize);
+    }
+}
+
+// M-mode setup every hart does for itself before dropping to S-mode.
+#[cfg(not(feature = "sbi"))]
+fn machine_init(hart_id: usize) {
     // Configure PMP to allow full access to all memory
     csr::write_pmpaddr0(0x3FFFFFFFFFFFFF); // Set PMP address to cover all memory
     csr::write_pmpcfg0(0xF); // Enable R/W/X permissions with NA4 address matching
@@ -29,7 +95,7 @@ fn main() -> ! {
     // - Bit 7: L=0 (Not locked, can be modified)
     // Alignment: NA4 means the region is aligned to a 4-byte boundary.
 
-    lib::task::scheduler::init();
+    lib::task::scheduler::init_hart(hart_id);
 
     csr::write_mtvec(kernel_trap as u64);
     csr::write_mideleg(lib::trap::interrupt::ENABLE_ALL_INTERRUPTS);
@@ -38,28 +104,41 @@ fn main() -> ! {
     // let kernel_task_struct = scheduler::get_kernel_task_struct();
     // csr::write_mscratch(kernel_task_struct as *const task::TaskStruct as u64);
     csr::mstatus_set_pp(PrivilegeMode::Supervisor);
-    csr::write_mepc(kernel as u64);
-    timer_init();
-    lib::mret!();
+}
+
+#[unsafe(no_mangle)]
+fn kernel() -> ! {
+    lib::mm::enable_paging();
+    // The shell waits for input most of the time, when it has some it should
+    // not queue behind background work.
+    lib::task::scheduler::task_create(shell::shell as *const u8, "shell".as_ptr(), 5, None, 0);
+    supervisor_init();
+    uart_init();
+    lib::sret!();
 
     loop {}
 }
 
 #[unsafe(no_mangle)]
-fn kernel() -> ! {
-    lib::task::scheduler::task_create(shell::shell as *const u8, "".as_ptr(), 0);
+fn secondary_kernel() -> ! {
+    lib::mm::enable_paging();
+    supervisor_init();
+    lib::sret!();
+
+    loop {}
+}
 
+fn supervisor_init() {
     csr::write_stvec(user_trap as u64);
     csr::sstatus_set_pp(PrivilegeMode::Supervisor);
 
     csr::write_sstatus(csr::read_sstatus() | (1 << csr::SSTATUS_SPIE)); // Enable S-mode interrupts after sret (switch to idle_task)
     csr::write_sie(csr::read_sie() | (1 << csr::SIE_SSIE)); // Enable software interrupt
     csr::write_sie(csr::read_sie() | (1 << csr::SIE_SEIE));
-    plic_init();
-    uart_init();
-    lib::sret!();
-
-    loop {}
+    if cfg!(feature = "sbi") || lib::timer::has_sstc() {
+        csr::write_sie(csr::read_sie() | (1 << csr::SIE_STIE));
+    }
+    plic_init(lib::task::scheduler::current_hart());
 }
 
 #[panic_handler]
@@ -75,6 +154,11 @@ fn panic(info: &PanicInfo<'_>) -> ! {
         lib::uart::print_string(message);
         lib::uart::print_char('\n');
     }
+    #[cfg(feature = "sbi")]
+    lib::sbi::system_reset(
+        lib::sbi::RESET_TYPE_SHUTDOWN,
+        lib::sbi::RESET_REASON_SYSTEM_FAILURE,
+    );
     // Infinite loop on panic
     loop {}
 }
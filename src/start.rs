@@ -1,18 +1,76 @@
 use core::arch::asm;
 
+#[cfg(not(feature = "sbi"))]
 #[unsafe(no_mangle)]
 fn _start() -> ! {
     unsafe {
         asm!(
             "csrr a0, mhartid",
-            "bnez a0, park",
+            "li t0, {max_harts}",
+            "bgeu a0, t0, park",
+            // Hart n boots on the n-th stack below stack_top.
             "la sp, stack_top",
+            "li t0, {boot_stack_size}",
+            "mul t0, t0, a0",
+            "sub sp, sp, t0",
+            "bnez a0, secondary_start",
+            // a1 still holds the device tree address for `main`.
             "j main",
+            max_harts = const lib::smp::MAX_HARTS,
+            boot_stack_size = const BOOT_STACK_SIZE,
             options(noreturn)
         )
     }
 }
 
+// The SBI firmware enters in S-mode with the hart id in a0 and the device
+// tree in a1, on whichever hart it picked to boot.
+#[cfg(feature = "sbi")]
+#[unsafe(no_mangle)]
+fn _start() -> ! {
+    unsafe {
+        asm!(
+            "li t0, {max_harts}",
+            "bgeu a0, t0, park",
+            "la sp, stack_top",
+            "li t0, {boot_stack_size}",
+            "mul t0, t0, a0",
+            "sub sp, sp, t0",
+            "j main",
+            max_harts = const lib::smp::MAX_HARTS,
+            boot_stack_size = const BOOT_STACK_SIZE,
+            options(noreturn)
+        )
+    }
+}
+
+// Where `hart_start` sends the other harts, again with the hart id in a0.
+#[cfg(feature = "sbi")]
+#[unsafe(no_mangle)]
+pub fn secondary_entry() -> ! {
+    unsafe {
+        asm!(
+            "la sp, stack_top",
+            "li t0, {boot_stack_size}",
+            "mul t0, t0, a0",
+            "sub sp, sp, t0",
+            "j secondary_start",
+            boot_stack_size = const BOOT_STACK_SIZE,
+            options(noreturn)
+        )
+    }
+}
+
+// Must match the boot stacks reserved in linker.ld.
+const BOOT_STACK_SIZE: usize = 0x4000;
+
+#[unsafe(no_mangle)]
+fn secondary_start(hart_id: usize) -> ! {
+    #[cfg(not(feature = "sbi"))]
+    lib::smp::wait_for_ipi();
+    crate::secondary_main(hart_id)
+}
+
 #[unsafe(no_mangle)]
 fn park() -> ! {
     unsafe {